@@ -0,0 +1,68 @@
+//! Check exercise definitions before uploading them to the database.
+//!
+//! Usage: validate_exercise <file>...
+//!
+//! The format is recognized by the extension of the file:
+//! Lua scripts (.lua) are checked with the static validator,
+//! declarative FSMs (.json, .fsm, .yaml, .yml) are loaded and validated against the builtin control factors.
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use motion::{validate_lua, ExerciseFormat, FsmExercise};
+use videopose::SKELETON_COCO_JOINTS;
+
+/// Validate a single file, returns false if it contains errors
+fn validate(path: &Path) -> bool {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let result = match ExerciseFormat::from_extension(extension) {
+        Some(ExerciseFormat::Lua) => match std::fs::read_to_string(path) {
+            Ok(script) => {
+                let issues = validate_lua(&script, SKELETON_COCO_JOINTS);
+                for issue in &issues {
+                    let severity = if issue.is_error() { "error" } else { "warning" };
+                    println!("{}: {}: {}", path.display(), severity, issue);
+                }
+                return !issues.iter().any(|i| i.is_error());
+            },
+            Err(e) => Err(e.to_string()),
+        },
+        Some(ExerciseFormat::Json) => FsmExercise::from_file(path, String::new(), String::new(), 0)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Some(ExerciseFormat::Yaml) => FsmExercise::from_yaml_file(path, SKELETON_COCO_JOINTS, String::new(), String::new(), 0)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        None => {
+            let extensions: Vec<String> = ExerciseFormat::EXTENSIONS.iter().map(|(e, _)| format!(".{}", e)).collect();
+            Err(format!("unknown format, expected {}", extensions.join(", ")))
+        },
+    };
+
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{}: error: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: validate_exercise <file>...");
+        return ExitCode::FAILURE;
+    }
+
+    let mut valid = true;
+    for path in &paths {
+        let ok = validate(Path::new(path));
+        if ok {
+            println!("{}: ok", path);
+        }
+        valid &= ok;
+    }
+
+    if valid { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
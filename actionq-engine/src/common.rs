@@ -1,12 +1,31 @@
 use serde::{Deserialize, Serialize};
+use motion::{CompletionCriteria, Parameters};
 
 /// Describes how many repetition for exercise
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RequestExerciseReps {
     pub exercise_id: String,
+    /// Repetitions of each set
     pub num_repetitions: u32,
+    /// Number of sets, each one with the same number of repetitions
+    #[serde(default = "default_sets")]
+    pub sets: u32,
+    /// Rest between two sets, in seconds
+    #[serde(default)]
+    pub rest_between_sets: f32,
+    /// Rest after the last set, before the next exercise, in seconds
+    #[serde(default)]
+    pub rest_after: f32,
+    /// Values of the parameters declared by the exercise, for example a target angle or the side to train
+    #[serde(default)]
+    pub parameters: Parameters,
+    /// When each set is complete, by default after the repetitions
+    #[serde(default)]
+    pub completion: CompletionCriteria,
 }
 
+fn default_sets() -> u32 { 1 }
+
 /// Possible requests from the client
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -9,18 +9,34 @@ use crate::common::{Request, RequestExerciseReps};
 
 /// Data definitions inside of firebase
 /// we use TitleCase
+#[allow(non_snake_case)]
 pub mod model {
     use serde::{Serialize, Deserialize};
     use crate::common::{Request, RequestExerciseReps};
     use std::collections::HashMap;
     
+    /// Format of the exercise definition stored in the 'fsm' field
+    pub use motion::ExerciseFormat;
+
     /// Exercise definition
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Exercise {
         pub name: String,
         pub description: String,
         pub gif: String,
-        pub fsm: String
+        pub fsm: String,
+        /// Missing in the older exercises, that are all Lua scripts
+        #[serde(default)]
+        pub format: ExerciseFormat,
+        /// Smoothing of the keypoints, when missing the engine default is used
+        #[serde(default)]
+        pub filter: Option<motion::FilterConfig>,
+        /// Joints detected with a lower confidence are considered missing, by default all the detected joints are used
+        #[serde(default)]
+        pub min_confidence: Option<f32>,
+        /// What to do when a required joint is missing from a frame, by default the frame is skipped
+        #[serde(default)]
+        pub missing_joints: motion::MissingJointPolicy,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -35,8 +51,51 @@ pub mod model {
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct FramePose {
+        /// Keypoints received from the pose estimator
         pub Keypoints: HashMap<String, (f32, f32)>,
-        pub FrameId: usize,
+        /// Keypoints evaluated by the exercise, only present if they were filtered
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub FilteredKeypoints: Option<HashMap<String, (f32, f32)>>,
+        /// Confidence of the pose estimator for each keypoint, missing in older recordings
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub Confidence: Option<HashMap<String, f32>>,
+        pub FrameId: u64,
+        /// Events emitted by the exercise in this frame
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub Events: Vec<motion::StateEvent>,
+        /// Capture time in seconds, missing in older recordings
+        #[serde(default)]
+        pub Timestamp: Option<f32>,
+    }
+
+    /// Statistics of a joint angle, in degrees
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct AngleStats {
+        pub Min: f32,
+        pub Max: f32,
+        pub Mean: f32,
+    }
+
+    /// A single repetition of an exercise, times are in seconds
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RepetitionRecord {
+        pub StartTime: f32,
+        pub EndTime: f32,
+        pub Duration: f32,
+        /// FrameId of the first and last pose of the repetition
+        pub FirstFrameId: u64,
+        pub LastFrameId: u64,
+        /// States visited in order
+        pub States: Vec<String>,
+        /// Number of frames in which each warning was raised
+        pub Warnings: HashMap<String, u32>,
+        /// Minimum and maximum of each delta
+        pub Delta: HashMap<String, (f32, f32)>,
+        /// Quality score from 0 to 100
+        pub Score: f32,
+        /// Range of motion of each joint, for example "left_elbow"
+        #[serde(default)]
+        pub RangeOfMotion: HashMap<String, AngleStats>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -44,7 +103,25 @@ pub mod model {
         pub Exercise: String,
         pub ExerciseTimestamp: String,
         pub NumRepetitionsDone: u32,
-        pub Poses: Vec<FramePose>
+        /// Seconds during which the exercise was evaluated, missing in older sessions
+        #[serde(default)]
+        pub ActiveTime: Option<f32>,
+        /// Number of the set starting from 1 and number of sets of the exercise, missing in older sessions
+        #[serde(default)]
+        pub Set: Option<(u32, u32)>,
+        /// Rest taken after this set, in seconds, missing without rest
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub RestTime: Option<f32>,
+        pub Poses: Vec<FramePose>,
+        /// Completed repetitions
+        #[serde(default)]
+        pub Repetitions: Vec<RepetitionRecord>,
+        /// Mean score of the repetitions, missing without repetitions
+        #[serde(default)]
+        pub Score: Option<f32>,
+        /// Range of motion of each joint during the whole exercise
+        #[serde(default)]
+        pub RangeOfMotion: HashMap<String, AngleStats>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -184,13 +261,13 @@ async fn on_event(event: FirestoreListenEvent, session: SessionProxy) {
 pub async fn listen_commands(patient_id: &str, database_id: &str, session: SessionProxy, mut cmds: Receiver<FirebaseCommand>) {
 
     tracing::info!("connecting to firestore database");
-    let firestore = Firestore::new(&database_id, &patient_id).await;
+    let firestore = Firestore::new(database_id, patient_id).await;
 
     // Add commands document for the patient
     tracing::info!("reseting commands document for patient");
     let doc = model::JetsonInterface { request: Some(Request::SessionEnd), state: model::JetsonState::Listening };
-    let doc_res: model::JetsonInterface = firestore.db.fluent().update().in_col("jetson")
-        .document_id(&patient_id).object(&doc)
+    let _: model::JetsonInterface = firestore.db.fluent().update().in_col("jetson")
+        .document_id(patient_id).object(&doc)
         .execute().await.expect("unable to add patient's command document");
 
     // Listen to collection's mutations
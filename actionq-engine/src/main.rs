@@ -1,6 +1,5 @@
 #![allow(dead_code, unused_imports)]
 
-use tokio;
 use tracing_subscriber::EnvFilter;
 use std::io::Read;
 
@@ -9,6 +8,7 @@ mod session;
 mod ui;
 mod firebase;
 mod common;
+mod replay;
 
 use firebase::FirebaseProxy;
 
@@ -38,16 +38,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let ui_proxy = ui::UiProxy(ui_tx);
 
     // Channel for firebase messages
-    let (firebase_tx, mut firebase_rx) = tokio::sync::mpsc::channel(100);
+    let (firebase_tx, firebase_rx) = tokio::sync::mpsc::channel(100);
     let firebase = FirebaseProxy(firebase_tx);
 
     // Move the tokio runtime to a different thread
     std::thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().expect("Unable to create Runtime");
-        let rt_enter = rt.enter();
+        let _rt_enter = rt.enter();
         rt.block_on(async {
 
-            let (pose, pose_receiver) = pose::run_human_pose_estimator();
+            let (pose, pose_receiver) = pose::run_human_pose_estimator(pose::create_pose_source());
             let session = session::run_session(&pose, pose_receiver, ui_proxy, firebase);
 
             // This is the control interface of the system
@@ -3,6 +3,7 @@ use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
 use videopose::*;
+use crate::replay::ReplaySource;
 
 // Receiver for HPE data
 #[derive(Debug)]
@@ -31,34 +32,71 @@ impl PoseProxy {
     }
 }
 
+/// Pose source used when ACTIONQ_POSE_SOURCE is not set
+#[cfg(feature = "jetson")]
+const DEFAULT_POSE_SOURCE: &str = "jetson";
+#[cfg(not(feature = "jetson"))]
+const DEFAULT_POSE_SOURCE: &str = "synthetic";
+
+/// Create the pose source selected by the ACTIONQ_POSE_SOURCE environment variable
+pub fn create_pose_source() -> Box<dyn PoseSource> {
+    let kind = std::env::var("ACTIONQ_POSE_SOURCE")
+        .unwrap_or_else(|_| DEFAULT_POSE_SOURCE.to_owned());
+
+    tracing::info!("using pose source: {}", kind);
+    match kind.as_str() {
+        #[cfg(feature = "jetson")]
+        "jetson" => Box::new(
+            JetsonSource::new(
+                "/home/nvidia/Repositories/actionq/networks/pose_resnet18_body.onnx",
+                "/home/nvidia/Repositories/actionq/networks/human_pose.json",
+                "/home/nvidia/Repositories/actionq/networks/colors.txt",
+                "/dev/video0",
+                "webrtc://@:8554/output"
+            ).expect("unable to create poseNet backend")
+        ),
+        #[cfg(not(feature = "jetson"))]
+        "jetson" => panic!("the jetson pose source requires building with the 'jetson' feature"),
+        "synthetic" => Box::new(SyntheticSource::default()),
+        "replay" => {
+            let path = std::env::var("ACTIONQ_REPLAY_FILE")
+                .expect("ACTIONQ_REPLAY_FILE is required by the replay source");
+            let speed = std::env::var("ACTIONQ_REPLAY_SPEED").ok()
+                .map(|s| s.parse().expect("ACTIONQ_REPLAY_SPEED must be a number"))
+                .unwrap_or(1.0);
+            let looping = std::env::var("ACTIONQ_REPLAY_LOOP").is_ok();
+            Box::new(
+                ReplaySource::from_file(std::path::Path::new(&path), speed, looping)
+                    .expect("unable to load recording")
+            )
+        },
+        _ => panic!("unknown pose source: {}", kind),
+    }
+}
+
 /// Pose estimator and analyzer
-#[derive(Debug)]
 struct Pose {
     cmd_receiver: mpsc::Receiver<Command>,
     data_sender: mpsc::Sender<FrameData>,
+    source: Box<dyn PoseSource>,
     is_running: bool,
 }
 
 impl Pose {
 
-    #[tracing::instrument]
-    pub fn instantiate() -> (Self, PoseProxy, mpsc::Receiver<FrameData>) {
+    #[tracing::instrument(skip_all)]
+    pub fn instantiate(source: Box<dyn PoseSource>) -> (Self, PoseProxy, mpsc::Receiver<FrameData>) {
         
         // Channel for commands
         let (cmd_sender, cmd_receiver) = mpsc::channel(100);
         // Channel for data output
         let (data_sender, data_receiver) = mpsc::channel(100);
 
-        videopose::create_hpe_engine(
-            "/home/nvidia/Repositories/actionq/networks/pose_resnet18_body.onnx",
-            "/home/nvidia/Repositories/actionq/networks/human_pose.json",
-            "/home/nvidia/Repositories/actionq/networks/colors.txt"
-        ).unwrap();
-
         (
             Pose {
                 cmd_receiver,
                 data_sender,
+                source,
                 is_running: false,
             },
             PoseProxy {
@@ -74,14 +112,14 @@ impl Pose {
             Command::InferenceStart => {
                 if !self.is_running {
                     tracing::info!("inference started");
-                    videopose::inference_start("/dev/video0", "webrtc://@:8554/output").unwrap();
+                    self.source.start().unwrap();
                     self.is_running = true;
                 }
             }
             Command::InferenceStop => {
                 if self.is_running {
                     tracing::info!("inference ended");
-                    videopose::inference_stop();
+                    self.source.stop();
                     self.is_running = false;
                 }
             }
@@ -95,9 +133,10 @@ impl Pose {
                 if self.is_running {
 
                     // Generate a pose estimation and output to channel
-                    let frame_data = videopose::inference_step().unwrap();
-                    if let Some(pose) = frame_data {
-                        self.data_sender.blocking_send(pose).unwrap();
+                    match self.source.step() {
+                        Ok(Some(pose)) => self.data_sender.blocking_send(pose).unwrap(),
+                        Ok(None) => {},
+                        Err(e) => tracing::error!("unable to process frame: {}", e),
                     }
 
                     // Try handle command
@@ -105,19 +144,17 @@ impl Pose {
                         self.handle_message(msg);
                     }
 
-                } else {
-                    if let Some(msg) = self.cmd_receiver.blocking_recv() {
-                        tracing::trace!("received message");
-                        self.handle_message(msg);
-                    }
+                } else if let Some(msg) = self.cmd_receiver.blocking_recv() {
+                    tracing::trace!("received message");
+                    self.handle_message(msg);
                 }
             }
         })
     }
 }
 
-pub fn run_human_pose_estimator() -> (PoseProxy, mpsc::Receiver<FrameData>) {
-    let (engine, proxy, pose_receiver) = Pose::instantiate();
+pub fn run_human_pose_estimator(source: Box<dyn PoseSource>) -> (PoseProxy, mpsc::Receiver<FrameData>) {
+    let (engine, proxy, pose_receiver) = Pose::instantiate(source);
     tokio::spawn(engine.run_pose_estimator());
     (proxy, pose_receiver)
 }
@@ -0,0 +1,284 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+use glam::Vec2;
+use serde::Deserialize;
+
+use videopose::{FrameData, Framebuffer, Pose, PoseSource, Result, SKELETON_COCO_JOINTS};
+use crate::firebase::model;
+
+/// Rate used to replay the recordings without timestamps
+const RECORDING_FPS: f32 = 5.0;
+
+/// A recording can be a whole exported session or a single exercise of a session
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Recording {
+    Session(model::Session),
+    Exercise(model::SessionExercise),
+}
+
+/// Pose source that replays the keypoints stored in a recorded session
+#[derive(Debug)]
+pub struct ReplaySource {
+    /// All recorded frames with their capture time in seconds, in order of execution
+    frames: Vec<(f32, Pose)>,
+    /// Replay speed, 1.0 is the original rate
+    speed: f32,
+    /// If true then restart from the first frame when the recording ends
+    looping: bool,
+    /// Next frame to replay
+    current: usize,
+    /// When the last frame was produced
+    last_time: Option<Instant>,
+    /// Added to the recorded times, keeps the timestamps monotonic when looping
+    time_offset: f32,
+    /// Number of frames produced
+    produced: u64,
+}
+
+impl ReplaySource {
+
+    /// Load a recording from a JSON file
+    #[tracing::instrument(err)]
+    pub fn from_file(path: &Path, speed: f32, looping: bool) -> Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        Self::from_json(&data, speed, looping)
+    }
+
+    /// Load a recording from a JSON string, either a session or a single session exercise.
+    /// The speed must be a finite number greater than zero
+    pub fn from_json(data: &str, speed: f32, looping: bool) -> Result<Self> {
+        if !speed.is_finite() || speed <= 0.0 {
+            return Err(format!("invalid replay speed {}, expected a number greater than zero", speed).into());
+        }
+
+        let exercises = match serde_json::from_str::<Recording>(data)? {
+            Recording::Session(session) => session.Exercises,
+            Recording::Exercise(exercise) => vec![exercise],
+        };
+
+        // Recordings without timestamps are assumed to be at a constant rate,
+        // the times are forced to be monotonic across the exercises
+        let mut last = 0.0f32;
+        let mut frames: Vec<(f32, Pose)> = exercises.iter()
+            .flat_map(|e| e.Poses.iter())
+            .enumerate()
+            .map(|(i, pose)| {
+                let time = pose.Timestamp.unwrap_or(i as f32 / RECORDING_FPS).max(last);
+                last = time;
+                (time, Self::pose_from_recording(pose))
+            })
+            .collect();
+
+        if frames.is_empty() {
+            return Err("the recording does not contain any frame".into());
+        }
+
+        // The replay starts at time zero
+        let first = frames[0].0;
+        frames.iter_mut().for_each(|(time, _)| *time -= first);
+
+        tracing::info!("loaded recording with {} frames from {} exercises", frames.len(), exercises.len());
+        Ok(Self {
+            frames,
+            speed,
+            looping,
+            current: 0,
+            last_time: None,
+            time_offset: 0.0,
+            produced: 0,
+        })
+    }
+
+    /// Convert the stored keypoints to the COCO order used by FrameData,
+    /// missing joints are placed at (0.0, 0.0) like the HPE does
+    fn keypoints_from_pose(pose: &model::FramePose) -> Vec<Vec2> {
+        SKELETON_COCO_JOINTS.iter()
+            .map(|joint| pose.Keypoints.get(*joint)
+                .map(|(x, y)| Vec2::new(*x, *y))
+                .unwrap_or(Vec2::ZERO))
+            .collect()
+    }
+
+    /// Convert the stored keypoints and their confidence to a pose,
+    /// older recordings without the confidence use 1.0 for the available keypoints
+    fn pose_from_recording(pose: &model::FramePose) -> Pose {
+        let keypoints = Self::keypoints_from_pose(pose);
+        match &pose.Confidence {
+            Some(confidence) => Pose {
+                keypoints,
+                confidence: SKELETON_COCO_JOINTS.iter()
+                    .map(|joint| confidence.get(*joint).copied().unwrap_or(0.0))
+                    .collect(),
+            },
+            None => Pose::from_keypoints(keypoints),
+        }
+    }
+
+    /// Time between the previous and the current frame at the replay speed
+    fn frame_interval(&self) -> Duration {
+        let (current, previous) = match self.current {
+            0 => return Duration::ZERO,
+            i if i >= self.frames.len() => return Duration::from_secs_f32(1.0 / RECORDING_FPS),
+            i => (self.frames[i].0, self.frames[i - 1].0),
+        };
+        Duration::from_secs_f32((current - previous) / self.speed)
+    }
+}
+
+impl PoseSource for ReplaySource {
+
+    /// The replay resumes from the last produced frame,
+    /// the session restarts the source between exercises
+    #[tracing::instrument(skip(self), err)]
+    fn start(&mut self) -> Result<()> {
+        tracing::info!("Start replay from frame {} of {} at speed {}", self.current, self.frames.len(), self.speed);
+        self.last_time = None;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    fn step(&mut self) -> Result<Option<FrameData>> {
+
+        // Wait until the next frame is due
+        let interval = self.frame_interval();
+        if let Some(last_time) = self.last_time {
+            std::thread::sleep(interval.saturating_sub(last_time.elapsed()));
+        }
+        self.last_time = Some(Instant::now());
+
+        if self.current >= self.frames.len() {
+            if !self.looping {
+                if self.current == self.frames.len() {
+                    tracing::info!("replay finished");
+                    self.current += 1;
+                }
+                return Ok(None);
+            }
+            self.time_offset += self.frames[self.frames.len() - 1].0 + 1.0 / RECORDING_FPS;
+            self.current = 0;
+        }
+
+        let (time, pose) = self.frames[self.current].clone();
+        self.current += 1;
+        self.produced += 1;
+
+        let mut framebuffer = Framebuffer::black(1280, 720);
+        framebuffer.draw_keypoints(&pose.keypoints);
+
+        Ok(Some(FrameData {
+            framebuffer,
+            poses: vec![pose],
+            timestamp: Duration::from_secs_f32(self.time_offset + time),
+            index: self.produced - 1,
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn stop(&mut self) {
+        tracing::info!("Stop replay at frame {}", self.current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Session of two exercises, the second starts with a timestamp before the end of the first
+    const RECORDING: &str = include_str!("../data/recording.json");
+
+    const EPSILON: f32 = 1e-4;
+
+    /// Position of a joint in the COCO order
+    fn coco(joint: &str) -> usize {
+        SKELETON_COCO_JOINTS.iter().position(|j| *j == joint).unwrap()
+    }
+
+    /// Replay all the frames of the source until it ends
+    fn replay(source: &mut ReplaySource) -> Vec<FrameData> {
+        source.start().unwrap();
+        std::iter::from_fn(|| source.step().unwrap()).collect()
+    }
+
+    #[test]
+    fn frames_are_replayed_in_order_from_time_zero() {
+        let mut source = ReplaySource::from_json(RECORDING, 100.0, false).unwrap();
+        let frames = replay(&mut source);
+
+        assert_eq!(frames.iter().map(|f| f.index).collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        let (wrist, knee) = (coco("left_wrist"), coco("right_knee"));
+        assert_eq!(frames[0].poses[0].keypoints[wrist], Vec2::new(100.0, 200.0));
+        assert_eq!(frames[2].poses[0].keypoints[wrist], Vec2::new(120.0, 180.0));
+        assert_eq!(frames[4].poses[0].keypoints[knee], Vec2::new(310.0, 400.0));
+
+        // The times are monotonic across the exercises
+        let times: Vec<f32> = frames.iter().map(|f| f.timestamp.as_secs_f32()).collect();
+        for (time, expected) in times.iter().zip([0.0, 0.1, 0.3, 0.3, 0.5]) {
+            assert!((time - expected).abs() < EPSILON, "{times:?}");
+        }
+    }
+
+    #[test]
+    fn confidence_is_replayed_or_derived_from_the_keypoints() {
+        let mut source = ReplaySource::from_json(RECORDING, 100.0, false).unwrap();
+        let frames = replay(&mut source);
+        let (wrist, knee) = (coco("left_wrist"), coco("right_knee"));
+        assert_eq!(frames[1].poses[0].confidence[wrist], 0.8);
+        assert_eq!(frames[1].poses[0].confidence[knee], 0.0);
+
+        // The second exercise was recorded without the confidence
+        assert_eq!(frames[3].poses[0].confidence[knee], 1.0);
+        assert_eq!(frames[3].poses[0].confidence[wrist], 0.0);
+    }
+
+    #[test]
+    fn frames_are_paced_by_their_timestamps() {
+        // Half a second of recording at double speed
+        let mut source = ReplaySource::from_json(RECORDING, 2.0, false).unwrap();
+        let start = Instant::now();
+        assert_eq!(replay(&mut source).len(), 5);
+        assert!(start.elapsed() >= Duration::from_millis(250), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn the_end_of_the_recording_stops_or_restarts_the_replay() {
+        let mut source = ReplaySource::from_json(RECORDING, 100.0, false).unwrap();
+        assert_eq!(replay(&mut source).len(), 5);
+        assert!(source.step().unwrap().is_none());
+
+        // Looping keeps the timestamps and the indices growing
+        let mut source = ReplaySource::from_json(RECORDING, 100.0, true).unwrap();
+        source.start().unwrap();
+        let frames: Vec<FrameData> = (0..7).map(|_| source.step().unwrap().unwrap()).collect();
+        assert_eq!(frames[5].index, 5);
+        assert!((frames[5].timestamp.as_secs_f32() - (0.5 + 1.0 / RECORDING_FPS)).abs() < EPSILON);
+        assert!((frames[6].timestamp.as_secs_f32() - (0.6 + 1.0 / RECORDING_FPS)).abs() < EPSILON);
+    }
+
+    #[test]
+    fn recordings_without_timestamps_use_a_constant_rate() {
+        let recording = r#"{"Exercise": "squat", "ExerciseTimestamp": "", "NumRepetitionsDone": 0, "Poses": [
+            {"FrameId": 0, "Keypoints": {"nose": [1.0, 1.0]}},
+            {"FrameId": 1, "Keypoints": {"nose": [2.0, 1.0]}}
+        ]}"#;
+        let mut source = ReplaySource::from_json(recording, 100.0, false).unwrap();
+        let frames = replay(&mut source);
+        assert_eq!(frames[1].timestamp, Duration::from_secs_f32(1.0 / RECORDING_FPS));
+    }
+
+    #[test]
+    fn malformed_recordings_are_rejected() {
+        let empty = r#"{"Timestamp": "", "Exercises": []}"#;
+        let no_poses = r#"{"Exercise": "squat", "ExerciseTimestamp": "", "NumRepetitionsDone": 0, "Poses": []}"#;
+        let wrong_keypoint = r#"{"Exercise": "squat", "ExerciseTimestamp": "", "NumRepetitionsDone": 0, "Poses": [
+            {"FrameId": 0, "Keypoints": {"nose": "up"}}
+        ]}"#;
+        for recording in ["", "not json", "[]", empty, no_poses, wrong_keypoint, &RECORDING[..RECORDING.len() / 2]] {
+            assert!(ReplaySource::from_json(recording, 1.0, false).is_err(), "{recording}");
+        }
+
+        for speed in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(ReplaySource::from_json(RECORDING, speed, false).is_err(), "{speed}");
+        }
+    }
+}
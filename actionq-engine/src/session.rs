@@ -6,6 +6,8 @@ use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use glam::Vec2;
 use std::ops::Deref;
+use std::time::Duration;
+use tokio::time::Instant;
 
 use crate::pose::{PoseEventSender, PoseEventSink, PoseProxy};
 use crate::common::RequestExerciseReps;
@@ -13,8 +15,8 @@ use crate::firebase::FirebaseProxy;
 use crate::ui::UiProxy;
 use crate::firebase::model;
 
-use videopose::{FrameData, Framebuffer, SKELETON_COCO_JOINTS};
-use motion::{LuaExercise, StateOutput, StateEvent, StateWarning, Skeleton};
+use videopose::{FrameData, Framebuffer, PatientSelection, PatientTracker, Pose, SKELETON_COCO_JOINTS};
+use motion::{validate_lua, Exercise, ExerciseError, FilterConfig, FsmExercise, Joint, LuaExercise, MotionError, Preprocessing, RangeOfMotion, StateOutput, StateEvent, StateWarning, Skeleton, ValidationIssue};
 
 pub enum Command {
     SessionStart {
@@ -27,15 +29,15 @@ pub enum Command {
     SessionEnd,
 }
 
-/// Creates a Skeleton from FrameData and a joint mapping
-pub fn framedata_to_skeleton(data: &FrameData, joints: &[&str]) -> Skeleton {
-    let mut result = HashMap::<String, Vec2>::new();
+/// Creates a Skeleton from the pose of a person and a joint mapping
+pub fn pose_to_skeleton(data: &Pose, joints: &[&str]) -> Skeleton {
+    let mut result = Skeleton::new();
     for (i, joint) in joints.iter().enumerate() {
         
-        // Skip all joint at position (0.0, 0.0) as they are not available
-        let coords = data.keypoints[i];
-        if coords.x != 0.0 && coords.y != 0.0 {
-            result.insert(String::from(*joint), data.keypoints[i]);
+        // Skip all joints not detected by the pose estimator
+        let confidence = data.confidence[i];
+        if confidence > 0.0 {
+            result.insert(String::from(*joint), Joint::new(data.keypoints[i], confidence));
         }
     }
     result
@@ -62,71 +64,327 @@ pub fn framedata_to_skeleton(data: &FrameData, joints: &[&str]) -> Skeleton {
 "neck" => 17
 */
 
-const DOWN:  Vec2 = Vec2::new( 0.0, -1.0);
-const UP:    Vec2 = Vec2::new( 0.0,  1.0);
-const LEFT:  Vec2 = Vec2::new(-1.0,  0.0);
-const RIGHT: Vec2 = Vec2::new( 1.0,  0.0);
+/// Check a Lua script before loading it, warnings are only logged
+fn validate_script(name: &str, script: &str) -> Result<(), ExerciseError> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = validate_lua(script, SKELETON_COCO_JOINTS)
+        .into_iter()
+        .partition(ValidationIssue::is_error);
+
+    for warning in &warnings {
+        tracing::warn!("exercise {}: {}", name, warning);
+    }
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        return Err(format!("invalid exercise {}: {}", name, errors.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// How the patient is chosen among the people in the scene, set by the ACTIONQ_PATIENT_SELECTION environment variable
+pub fn patient_selection() -> PatientSelection {
+    std::env::var("ACTIONQ_PATIENT_SELECTION")
+        .map(|name| name.parse().expect("ACTIONQ_PATIENT_SELECTION must be largest, central or first"))
+        .unwrap_or_default()
+}
+
+/// Keypoint filter of the exercises that do not select one, set by the ACTIONQ_FILTER environment variable
+pub fn default_filter() -> FilterConfig {
+    std::env::var("ACTIONQ_FILTER")
+        .map(|name| name.parse().expect("ACTIONQ_FILTER must be none, one_euro, ema or median"))
+        .unwrap_or_default()
+}
+
+/// Create the exercise with the backend required by the format of its definition, configured by the request
+/// of the session. The keypoints are smoothed with the filter of the exercise or with the default one.
+/// The parameters of the session are only supported by the Lua scripts
+pub fn load_exercise(descriptor: model::Exercise, request: &RequestExerciseReps, default_filter: FilterConfig) -> Result<Box<dyn Exercise>, ExerciseError> {
+    let model::Exercise { name, description, fsm, format, filter, min_confidence, missing_joints, .. } = descriptor;
+    let RequestExerciseReps { num_repetitions: repetitions_target, parameters, completion, .. } = request;
+    let preprocessing = Preprocessing::default()
+        .with_filter(filter.unwrap_or(default_filter))
+        .with_min_confidence(min_confidence.unwrap_or(0.0))
+        .with_missing_joint_policy(missing_joints);
+    if format != model::ExerciseFormat::Lua && !parameters.is_empty() {
+        return Err(format!("exercise {} does not accept parameters", name).into());
+    }
+    Ok(match format {
+        model::ExerciseFormat::Lua => {
+            validate_script(&name, &fsm)?;
+            Box::new(LuaExercise::from_string(fsm, name, description, *repetitions_target)?
+                .with_parameters(parameters)?
+                .with_completion(completion.clone())?
+                .with_preprocessing(preprocessing))
+        },
+        model::ExerciseFormat::Json => Box::new(FsmExercise::from_string(fsm, name, description, *repetitions_target)?
+            .with_completion(completion.clone())?
+            .with_preprocessing(preprocessing)),
+        model::ExerciseFormat::Yaml => Box::new(FsmExercise::from_yaml(fsm, SKELETON_COCO_JOINTS, name, description, *repetitions_target)?
+            .with_completion(completion.clone())?
+            .with_preprocessing(preprocessing)),
+    })
+}
+
+/// Set of an exercise in the prescription of the session
+#[derive(Debug, Clone, Copy)]
+struct PlannedSet {
+    /// Number of the set, starting from 1
+    set: u32,
+    /// Number of sets of the exercise
+    sets: u32,
+    /// Rest prescribed after the set
+    rest: Duration,
+    /// Rest actually taken after the set, shorter if the session ended during the rest
+    rested: Option<Duration>,
+}
+
+/// Rest between two sets or two exercises, the countdown stops while the session is paused
+#[derive(Debug, Clone, Copy)]
+struct RestPeriod {
+    /// Rest prescribed
+    duration: Duration,
+    /// Rest taken before the last pause
+    elapsed: Duration,
+    /// Since when the countdown is running, None while the session is paused
+    counting_since: Option<Instant>,
+}
+
+impl RestPeriod {
+    fn new(duration: Duration, now: Instant, running: bool) -> Self {
+        Self { duration, elapsed: Duration::ZERO, counting_since: running.then_some(now) }
+    }
+
+    /// Rest taken until now, at most the prescribed one
+    fn elapsed(&self, now: Instant) -> Duration {
+        let counting = self.counting_since.map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        (self.elapsed + counting).min(self.duration)
+    }
+
+    /// Rest left from now
+    fn remaining(&self, now: Instant) -> Duration {
+        self.duration - self.elapsed(now)
+    }
+
+    fn pause(&mut self, now: Instant) {
+        self.elapsed = self.elapsed(now);
+        self.counting_since = None;
+    }
+
+    fn resume(&mut self, now: Instant) {
+        self.counting_since.get_or_insert(now);
+    }
+
+    /// When the rest ends, None while the session is paused
+    fn deadline(&self) -> Option<Instant> {
+        self.counting_since.map(|since| since + (self.duration - self.elapsed))
+    }
+}
 
 #[derive(Debug)]
 struct SessionState {
     /// All the exercises to execute during this session
-    pub exercises: Vec<LuaExercise>,
+    pub exercises: Vec<Box<dyn Exercise>>,
     /// The currently active exercise index
     pub current_idx: usize,
     /// If true then run analyzer and store logs, otherwise skip frames analysis
     pub running: bool,
+    /// Required joints missing from the last frame, shown to the patient
+    pub missing_joints: Vec<String>,
+    /// Set of each exercise, in the same order of the exercises
+    sets: Vec<PlannedSet>,
+    /// Rest in progress before the current exercise
+    rest: Option<RestPeriod>,
 }
 
 impl SessionState {
     /// Process a frame, returns the following:
     /// - exercise_is_complete, session_is_complete, StateOutput
-    pub fn process(&mut self, skeleton: &Skeleton) -> (bool, bool, Option<StateOutput>) {
+    pub fn process(&mut self, skeleton: &Skeleton, index: u64, time: f32) -> Result<(bool, bool, Option<StateOutput>), MotionError> {
 
         let exercise = &mut self.exercises[self.current_idx];
-        let (finished, output) = exercise.process(&skeleton)
-            .expect("Unable to process current frame");
+        let (finished, output) = exercise.process(skeleton, index, time)?;
 
-        let mut completed = false;
-        if finished {
-            if self.current_idx < self.exercises.len() - 1 {
-                self.current_idx += 1;
-            } else {
-                completed = true;
-            }
-        } 
+        let completed = finished && self.next_exercise();
+        Ok((finished, completed, output))
+    }
+
+    /// End the current exercise and move to the next one, returns true if the session is complete
+    pub fn next_exercise(&mut self) -> bool {
+        self.teardown_exercise();
+        if self.current_idx < self.exercises.len() - 1 {
+            self.current_idx += 1;
+            // The UI forgets the missing joints when the exercise ends, they must be sent again
+            self.missing_joints.clear();
+            false
+        } else {
+            true
+        }
+    }
 
-        (finished, completed, output)
+    /// Start the current exercise, fails if the script cannot set it up
+    pub fn setup_exercise(&mut self) -> Result<(), MotionError> {
+        self.exercises[self.current_idx].setup()
+    }
+
+    /// End the current exercise, an error of the script is only logged
+    pub fn teardown_exercise(&mut self) {
+        if let Err(error) = self.exercises[self.current_idx].teardown() {
+            tracing::error!("{}", error);
+        }
+    }
+
+    /// Pause or resume the current exercise, an error of the script is only logged.
+    /// During a rest the exercise has not started yet, only the countdown is paused or resumed
+    pub fn set_running(&mut self, running: bool) {
+        if running == self.running {
+            return;
+        }
+        self.running = running;
+
+        if let Some(rest) = self.rest.as_mut() {
+            if running { rest.resume(Instant::now()) } else { rest.pause(Instant::now()) }
+            return;
+        }
+
+        let exercise = &mut self.exercises[self.current_idx];
+        let result = if running { exercise.resume() } else { exercise.pause() };
+        if let Err(error) = result {
+            tracing::error!("{}", error);
+        }
     }
 
     /// Get current exercise name
     pub fn current_exercise_name(&self) -> String {
-        self.exercises[self.current_idx].name.clone()
+        self.exercises[self.current_idx].name().to_owned()
+    }
+
+    /// Name of the current exercise with its set, shown to the patient
+    pub fn current_exercise_label(&self) -> String {
+        let PlannedSet { set, sets, .. } = self.sets[self.current_idx];
+        match sets {
+            1 => self.current_exercise_name(),
+            _ => format!("{} (serie {} di {})", self.current_exercise_name(), set, sets),
+        }
+    }
+
+    /// Start the rest prescribed after the previous exercise, returns its duration
+    /// or None if the current exercise can start immediately
+    pub fn start_rest(&mut self) -> Option<Duration> {
+        let previous = self.current_idx.checked_sub(1)?;
+        let rest = self.sets[previous].rest;
+        if rest.is_zero() {
+            return None;
+        }
+        self.rest = Some(RestPeriod::new(rest, Instant::now(), self.running));
+        Some(rest)
+    }
+
+    /// End the rest in progress and record how long it lasted, without the pauses
+    pub fn end_rest(&mut self) {
+        if let Some(rest) = self.rest.take() {
+            self.sets[self.current_idx - 1].rested = Some(rest.elapsed(Instant::now()));
+        }
+    }
+
+    /// True during the rest before the current exercise
+    pub fn is_resting(&self) -> bool {
+        self.rest.is_some()
+    }
+
+    /// When the rest in progress ends, None without a rest or while the session is paused
+    pub fn rest_deadline(&self) -> Option<Instant> {
+        self.rest.and_then(|rest| rest.deadline())
+    }
+
+    /// Rest left before the current exercise
+    pub fn rest_remaining(&self) -> Option<Duration> {
+        self.rest.map(|rest| rest.remaining(Instant::now()))
     }
 
     /// get current exercise
     pub fn current_repetitions(&self) -> (u32, u32) {
-        let ex = &self.exercises[self.current_idx];
-        (ex.repetitions_target, ex.repetitions)
+        let (repetitions, repetitions_target) = self.exercises[self.current_idx].repetitions();
+        (repetitions_target, repetitions)
+    }
+
+    /// Update the required joints missing from the last frame of the current exercise,
+    /// returns true if they changed
+    pub fn update_missing_joints(&mut self) -> bool {
+        let missing = self.exercises[self.current_idx].missing_joints();
+        if missing == self.missing_joints.as_slice() {
+            return false;
+        }
+        self.missing_joints = missing.to_vec();
+        true
+    }
+
+    /// Seconds left to complete the current exercise, only for the exercises completed by time
+    pub fn current_remaining_time(&self) -> Option<f32> {
+        self.exercises[self.current_idx].completion().remaining_time()
+    }
+
+    /// Score of the last repetition of the current exercise
+    pub fn current_score(&self) -> Option<f32> {
+        self.exercises[self.current_idx].repetition_log().completed().last().map(|r| r.score)
     }
 
 }
 
-/// Convert a LuaExercise into a Firestore SessionExercise
-impl From<&LuaExercise> for model::SessionExercise {
-    fn from(other: &LuaExercise) -> Self {
+/// Convert the keypoints of a skeleton into the Firestore format
+fn keypoints_to_model(skeleton: &Skeleton) -> HashMap<String, (f32, f32)> {
+    skeleton.iter()
+        .map(|(k, v)| (k.clone(), (v.position.x, v.position.y)))
+        .collect()
+}
+
+/// Convert a range of motion into the Firestore format
+fn range_of_motion_to_model(rom: &RangeOfMotion) -> HashMap<String, model::AngleStats> {
+    rom.iter()
+        .map(|(joint, s)| (joint.clone(), model::AngleStats { Min: s.min, Max: s.max, Mean: s.mean }))
+        .collect()
+}
+
+/// Convert an exercise into a Firestore SessionExercise
+impl From<&dyn Exercise> for model::SessionExercise {
+    fn from(other: &dyn Exercise) -> Self {
+        let rom = motion::rom::analyze(other);
         Self {
-            Exercise: other.name.clone(),
+            Exercise: other.name().to_owned(),
             ExerciseTimestamp: String::new(),
-            NumRepetitionsDone: other.repetitions,
-            Poses: other.frames.iter().enumerate()
-                .map(|(i, (sk, _))| {
+            NumRepetitionsDone: other.repetitions().0,
+            ActiveTime: Some(other.completion().active_time()),
+            Set: None,
+            RestTime: None,
+            Poses: other.frames().iter()
+                .map(|frame| {
                     model::FramePose {
-                        FrameId: i,
-                        Keypoints: sk.iter()
-                            .map(|(k, v)| (k.clone(), (v.x, v.y)))
-                            .collect()
+                        FrameId: frame.index,
+                        Timestamp: Some(frame.time),
+                        Keypoints: keypoints_to_model(frame.raw_skeleton.as_ref().unwrap_or(&frame.skeleton)),
+                        FilteredKeypoints: frame.raw_skeleton.as_ref().map(|_| keypoints_to_model(&frame.skeleton)),
+                        Confidence: Some(frame.raw_skeleton.as_ref().unwrap_or(&frame.skeleton).iter()
+                            .map(|(k, v)| (k.clone(), v.confidence))
+                            .collect()),
+                        Events: frame.output.metadata.events.clone(),
                     }
-                }).collect()
+                }).collect(),
+            Repetitions: other.repetition_log().completed().iter()
+                .zip(&rom.repetitions)
+                .map(|(r, r_rom)| model::RepetitionRecord {
+                    StartTime: r.start,
+                    EndTime: r.end,
+                    Duration: r.duration(),
+                    FirstFrameId: other.frames()[r.frames.start].index,
+                    LastFrameId: other.frames()[r.frames.end - 1].index,
+                    States: r.states.clone(),
+                    Warnings: r.warnings.clone(),
+                    Delta: r.delta.iter()
+                        .map(|(k, b)| (k.clone(), (b.min, b.max)))
+                        .collect(),
+                    Score: r.score,
+                    RangeOfMotion: range_of_motion_to_model(r_rom),
+                }).collect(),
+            Score: other.repetition_log().score(),
+            RangeOfMotion: range_of_motion_to_model(&rom.exercise),
         }
     }
 }
@@ -137,7 +395,12 @@ impl From<&SessionState> for model::Session {
         Self {
             Timestamp: String::new(),
             Exercises: other.exercises.iter()
-                .map(model::SessionExercise::from)
+                .zip(&other.sets)
+                .map(|(e, s)| model::SessionExercise {
+                    Set: Some((s.set, s.sets)),
+                    RestTime: s.rested.map(|rest| rest.as_secs_f32()),
+                    ..model::SessionExercise::from(e.as_ref())
+                })
                 .collect()
         }
     }
@@ -160,6 +423,10 @@ struct Session {
     ui: UiProxy,
     /// Proxy to command the firebase database
     firebase: FirebaseProxy,
+    /// Keypoint filter of the exercises that do not select one
+    default_filter: FilterConfig,
+    /// Follows the patient among the people in the scene, only the patient is analyzed
+    tracker: PatientTracker,
 
     // Broadcast the pose analysis, useful in future for more developed UIs 
     //_data_sender: broadcast::Sender<SessionPoseData>,
@@ -215,7 +482,9 @@ impl Session {
                 session: None,
                 pose: pose.clone(),
                 ui,
-                firebase
+                firebase,
+                default_filter: default_filter(),
+                tracker: PatientTracker::new(patient_selection()),
             },
             SessionProxy(tx),
         )
@@ -227,9 +496,9 @@ impl Session {
     }
 
     #[tracing::instrument(skip_all, fields(exercises, save))]
-    async fn session_start(&mut self, exercises: Vec<RequestExerciseReps>, save: bool) {
+    async fn session_start(&mut self, exercises: Vec<RequestExerciseReps>, _save: bool) {
         // There is already a session active!
-        if let Some(_) = &self.session {
+        if self.session.is_some() {
             tracing::warn!("invalid state for session start");
             return;
         }
@@ -238,16 +507,50 @@ impl Session {
         self.send_cec_signal()
             .expect("unable to turn on TV");
 
-        // Load exercises collection
-        let mut states: Vec<LuaExercise> = vec![];
+        // Load exercises collection, each set is a separate exercise
+        let mut states: Vec<Box<dyn Exercise>> = vec![];
+        let mut sets: Vec<PlannedSet> = vec![];
         for e in &exercises {
 
             // Obtain the exercise descriptor from the database
             let descriptor = self.firebase.get_exercise(&e.exercise_id).await;
             if let Some(descriptor) = descriptor {
-                tracing::info!("loaded descriptor for exercise {}", &e.exercise_id);
-                states.push(LuaExercise::from_string(descriptor.fsm, descriptor.name, descriptor.description, e.num_repetitions)
-                                .expect("Unable to create LuaExercise"));
+                tracing::info!("loaded {:?} descriptor for exercise {}", descriptor.format, &e.exercise_id);
+                let total_sets = e.sets.max(1);
+                for set in 1..=total_sets {
+
+                    // A rest that is negative, not a number or too long rejects the whole session
+                    let seconds = if set < total_sets { e.rest_between_sets } else { e.rest_after };
+                    let Ok(rest) = Duration::try_from_secs_f32(seconds) else {
+                        tracing::error!("invalid rest of {} seconds for exercise {}", seconds, e.exercise_id);
+                        self.ui.show_error(format!("Pausa non valida per l'esercizio {}: {} secondi", e.exercise_id, seconds)).await;
+                        return;
+                    };
+
+                    match load_exercise(descriptor.clone(), e, self.default_filter) {
+                        Ok(exercise) => {
+                            states.push(exercise);
+                            sets.push(PlannedSet {
+                                set,
+                                sets: total_sets,
+                                rest,
+                                rested: None,
+                            });
+                        },
+                        Err(error) if set == 1 => {
+                            // Skip the exercise, the rest of the session can still run
+                            tracing::error!("unable to create exercise {}: {}", e.exercise_id, error);
+                            self.ui.show_error(format!("Esercizio {} saltato: {}", e.exercise_id, error)).await;
+                            break;
+                        },
+                        Err(error) => {
+                            // The prescription cannot be followed, the session does not start
+                            tracing::error!("unable to create set {} of exercise {}: {}", set, e.exercise_id, error);
+                            self.ui.show_error(format!("Serie {} dell'esercizio {} non valida, sessione annullata: {}", set, e.exercise_id, error)).await;
+                            return;
+                        }
+                    }
+                }
 
             } else {
                 tracing::error!("unable to find exercise with id: {}", e.exercise_id);
@@ -255,28 +558,42 @@ impl Session {
             }
         }
 
+        if states.is_empty() {
+            tracing::error!("no exercise can be executed");
+            return;
+        }
+
         self.session = Some(
             SessionState {
                 current_idx: 0,
                 running: true,
-                exercises: states
+                exercises: states,
+                missing_joints: vec![],
+                sets,
+                rest: None,
             }
         );
 
-        // Notify other actors to start HPE inference and visualization 
-        self.pose.inference_start().await;
-        let session = self.session.as_ref().unwrap();
-        self.ui.exercise_show(session.current_exercise_name()).await;
-        self.ignore_frames = false;
+        // The patient is selected again in the first frames of the session
+        self.tracker.reset();
 
+        self.ignore_frames = false;
         tracing::info!("session started");
+
+        // Notify other actors to start HPE inference and visualization 
+        self.exercise_start().await;
     }
 
     #[tracing::instrument(skip_all, fields(running))]
-    fn set_play_state(&mut self, running: bool) {
+    async fn set_play_state(&mut self, running: bool) {
         if let Some(session) = self.session.as_mut() {
             tracing::info!("set play state to {}", running);
-            session.running = running;
+            session.set_running(running);
+
+            // The countdown of the rest stops during the pause
+            if let Some(remaining) = session.rest_remaining() {
+                self.ui.rest_show(remaining, session.current_exercise_label(), !running).await;
+            }
         } else {
             tracing::warn!("invalid state for changing play state: no session active");
         }
@@ -284,7 +601,12 @@ impl Session {
 
     #[tracing::instrument(skip_all)]
     async fn session_end(&mut self) {
-        if let Some(session) = &self.session {
+        if let Some(session) = &mut self.session {
+            // During a rest the current exercise has not started yet
+            if !session.is_resting() {
+                session.teardown_exercise();
+            }
+            session.end_rest();
 
             // Notify other actors to stop
             self.pose.inference_end().await;
@@ -292,7 +614,7 @@ impl Session {
             self.ignore_frames = true;
 
             // Save to database
-            self.firebase.store_session(model::Session::from(session)).await;
+            self.firebase.store_session(model::Session::from(&*session)).await;
 
             self.session = None;
             tracing::info!("session ended");   
@@ -300,21 +622,59 @@ impl Session {
         }
     }
 
+    /// Start the current exercise once the rest before it is over
+    #[tracing::instrument(skip_all)]
+    async fn rest_end(&mut self) {
+        if let Some(session) = self.session.as_mut() {
+            session.end_rest();
+            tracing::info!("rest ended");
+
+            // The patient may have moved during the rest, select them again
+            self.tracker.reset();
+            self.exercise_start().await;
+        }
+    }
+
+    /// Set up the current exercise and show it to the patient,
+    /// the exercises that cannot be set up are skipped
+    async fn exercise_start(&mut self) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        while let Err(error) = session.setup_exercise() {
+            tracing::error!("skipping exercise: {}", error);
+            self.ui.show_error(format!("Esercizio saltato: {}", error)).await;
+            if session.next_exercise() {
+                tracing::info!("session completed");
+                self.session_end().await;
+                return;
+            }
+        }
+
+        self.pose.inference_start().await;
+        self.ui.exercise_show(session.current_exercise_name()).await;
+    }
+
     #[tracing::instrument(skip_all, fields(cmd))]
     async fn handle_command(&mut self, cmd: Command) {
         match cmd {
             Command::SessionStart { exercises, save } => self.session_start(exercises, save).await,
-            Command::SetPlayState { running } => self.set_play_state(running),
+            Command::SetPlayState { running } => self.set_play_state(running).await,
             Command::SessionEnd => self.session_end().await,
-            _ => todo!(),
         }
     }
 
     #[tracing::instrument(skip_all)]
     async fn run_session(mut self) {
         loop {
+            let rest_deadline = self.session.as_ref().and_then(SessionState::rest_deadline);
             tokio::select! {
 
+                // Start the next exercise at the end of the rest
+                _ = tokio::time::sleep_until(rest_deadline.unwrap_or_else(Instant::now)), if rest_deadline.is_some() => {
+                    self.rest_end().await;
+                },
+
                 // Handle commands from other actors
                 cmd_data = self.receiver.recv() => {
                     if let Some(cmd) = cmd_data {
@@ -324,30 +684,44 @@ impl Session {
 
                 // Handle data from pose estimator
                 pose_data = self.pose_receiver.recv() => {
-                    if self.ignore_frames {
+                    if self.ignore_frames || self.session.as_ref().is_some_and(SessionState::is_resting) {
                         continue;
                     }
 
-                    // TODO: use real deltatime
-                    const DELTATIME: f32 = 0.2;
-
-                    // Analyze only if there is a subject
+                    // Analyze only if the patient is in the scene
                     if let Some(pose_prepose) = pose_data {
-                        if pose_prepose.subjects != 0 {
+                        if let Some(patient) = self.tracker.track(&pose_prepose) {
 
                             // If the pose estimator is running then we must have a current session!
                             let session = self.session.as_mut().expect("");
                             if session.running {
                                 tracing::trace!("running exercise analyzer");
 
-                                let skeleton = framedata_to_skeleton(&pose_prepose, SKELETON_COCO_JOINTS);
-                                let (finished, completed, output) = session.process(&skeleton);
+                                let skeleton = pose_to_skeleton(&pose_prepose.poses[patient], SKELETON_COCO_JOINTS);
+                                let time = pose_prepose.timestamp.as_secs_f32();
+                                let (finished, completed, output) = match session.process(&skeleton, pose_prepose.index, time) {
+                                    Ok(result) => result,
+                                    Err(error) => {
+                                        // A broken exercise must not stop the session, skip it
+                                        tracing::error!("skipping exercise: {}", error);
+                                        self.ui.show_error(format!("Esercizio saltato: {}", error)).await;
+                                        (true, session.next_exercise(), None)
+                                    }
+                                };
                                 let (repetitions_target, repetitions) = session.current_repetitions();
+                                let remaining_time = session.current_remaining_time();
+                                let score = session.current_score();
+
+                                // Ask the patient to move back into the frame
+                                if session.update_missing_joints() {
+                                    tracing::debug!("missing joints: {:?}", session.missing_joints);
+                                    self.ui.missing_joints(session.missing_joints.clone()).await;
+                                }
 
                                 //println!("{:?}", output);
 
                                 // Send progress to UI
-                                self.ui.update(output, repetitions_target, repetitions, pose_prepose).await;
+                                self.ui.update(output, repetitions_target, repetitions, score, remaining_time, pose_prepose).await;
                                 match (finished, completed) {
                                     // Close session
                                     (true, true) => {
@@ -364,8 +738,13 @@ impl Session {
                                         self.pose.inference_end().await;
                                         self.ui.exercise_stop().await;
 
-                                        self.pose.inference_start().await;
-                                        self.ui.exercise_show(session.current_exercise_name()).await;
+                                        // Rest before the next set or exercise if prescribed
+                                        if let Some(rest) = session.start_rest() {
+                                            tracing::info!("rest for {:?}", rest);
+                                            self.ui.rest_show(rest, session.current_exercise_label(), false).await;
+                                        } else {
+                                            self.exercise_start().await;
+                                        }
                                     },
                                     _ => {}
                                 }
@@ -385,3 +764,148 @@ pub fn run_session(pose: &PoseProxy, pose_receiver: mpsc::Receiver<FrameData>, u
     tokio::spawn(session.run_session());
     proxy
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use motion::{Completion, Frame, RepetitionLog};
+
+    /// Calls of the hooks of the exercises, in order
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// Exercise finished by its first frame, records the calls of its hooks
+    #[derive(Debug)]
+    struct Scripted {
+        name: String,
+        calls: Calls,
+        repetition_log: RepetitionLog,
+        completion: Completion,
+    }
+
+    impl Scripted {
+        fn record(&self, hook: &str) -> Result<(), MotionError> {
+            self.calls.lock().unwrap().push(format!("{} {}", hook, self.name));
+            Ok(())
+        }
+    }
+
+    impl Exercise for Scripted {
+        fn process(&mut self, _skeleton: &Skeleton, _index: u64, _time: f32) -> Result<(bool, Option<StateOutput>), MotionError> {
+            Ok((true, None))
+        }
+        fn name(&self) -> &str { &self.name }
+        fn description(&self) -> &str { "" }
+        fn repetitions(&self) -> (u32, u32) { (0, 0) }
+        fn repetition_log(&self) -> &RepetitionLog { &self.repetition_log }
+        fn current_state(&self) -> &str { "" }
+        fn required_joints(&self) -> &[String] { &[] }
+        fn missing_joints(&self) -> &[String] { &[] }
+        fn frames(&self) -> &[Frame] { &[] }
+        fn completion(&self) -> &Completion { &self.completion }
+        fn setup(&mut self) -> Result<(), MotionError> { self.record("setup") }
+        fn pause(&mut self) -> Result<(), MotionError> { self.record("pause") }
+        fn resume(&mut self) -> Result<(), MotionError> { self.record("resume") }
+        fn teardown(&mut self) -> Result<(), MotionError> { self.record("teardown") }
+    }
+
+    /// Running session with a set for each (exercise, set, sets, rest after the set in seconds)
+    fn session(plan: &[(&str, u32, u32, u64)]) -> (SessionState, Calls) {
+        let calls = Calls::default();
+        let exercises = plan.iter()
+            .map(|(name, ..)| Box::new(Scripted {
+                name: name.to_string(),
+                calls: calls.clone(),
+                repetition_log: RepetitionLog::default(),
+                completion: Completion::default(),
+            }) as Box<dyn Exercise>)
+            .collect();
+        let sets = plan.iter()
+            .map(|(_, set, sets, rest)| PlannedSet { set: *set, sets: *sets, rest: Duration::from_secs(*rest), rested: None })
+            .collect();
+        let state = SessionState { exercises, current_idx: 0, running: true, missing_joints: vec![], sets, rest: None };
+        (state, calls)
+    }
+
+    /// Process an empty frame, returns if the exercise and the session are complete
+    fn step(state: &mut SessionState) -> (bool, bool) {
+        let (finished, completed, _) = state.process(&Skeleton::new(), 0, 0.0).unwrap();
+        (finished, completed)
+    }
+
+    /// Calls recorded since the last time
+    fn take(calls: &Calls) -> Vec<String> {
+        std::mem::take(&mut *calls.lock().unwrap())
+    }
+
+    #[test]
+    fn sets_run_in_order_with_their_rest() {
+        let (mut state, calls) = session(&[("squat", 1, 2, 30), ("squat", 2, 2, 0), ("lunge", 1, 1, 60)]);
+        assert_eq!(state.start_rest(), None);
+        assert_eq!(state.current_exercise_label(), "squat (serie 1 di 2)");
+
+        // Rest between the sets
+        assert_eq!(step(&mut state), (true, false));
+        assert_eq!(state.current_exercise_label(), "squat (serie 2 di 2)");
+        assert_eq!(state.start_rest(), Some(Duration::from_secs(30)));
+        assert!(state.is_resting());
+        state.end_rest();
+        assert!(!state.is_resting());
+
+        // No rest between the exercises
+        assert_eq!(step(&mut state), (true, false));
+        assert_eq!(state.current_exercise_label(), "lunge");
+        assert_eq!(state.start_rest(), None);
+
+        // The rest after the last exercise is not taken
+        assert_eq!(step(&mut state), (true, true));
+        assert_eq!(take(&calls), ["teardown squat", "teardown squat", "teardown lunge"]);
+    }
+
+    #[test]
+    fn rest_countdown_stops_during_the_pause() {
+        let start = Instant::now();
+        let mut rest = RestPeriod::new(Duration::from_secs(30), start, true);
+        assert_eq!(rest.deadline(), Some(start + Duration::from_secs(30)));
+
+        rest.pause(start + Duration::from_secs(10));
+        assert_eq!(rest.deadline(), None);
+        assert_eq!(rest.elapsed(start + Duration::from_secs(50)), Duration::from_secs(10));
+
+        rest.resume(start + Duration::from_secs(50));
+        assert_eq!(rest.deadline(), Some(start + Duration::from_secs(70)));
+        assert_eq!(rest.remaining(start + Duration::from_secs(60)), Duration::from_secs(10));
+        assert_eq!(rest.elapsed(start + Duration::from_secs(100)), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn pause_during_the_rest_does_not_reach_the_exercise() {
+        let (mut state, calls) = session(&[("squat", 1, 2, 30), ("squat", 2, 2, 0)]);
+        state.set_running(false);
+        state.set_running(true);
+        assert_eq!(take(&calls), ["pause squat", "resume squat"]);
+
+        step(&mut state);
+        take(&calls);
+        state.start_rest();
+        state.set_running(false);
+        assert_eq!(state.rest_deadline(), None);
+        assert!(state.rest_remaining().is_some());
+        state.set_running(true);
+        assert!(state.rest_deadline().is_some());
+        assert!(take(&calls).is_empty());
+    }
+
+    #[test]
+    fn rest_taken_is_stored_with_the_set() {
+        let (mut state, _) = session(&[("squat", 1, 2, 30), ("squat", 2, 2, 0)]);
+        step(&mut state);
+        state.start_rest();
+        state.end_rest();
+
+        let session = model::Session::from(&state);
+        assert_eq!(session.Exercises.iter().map(|e| e.Set).collect::<Vec<_>>(), [Some((1, 2)), Some((2, 2))]);
+        assert!(session.Exercises[0].RestTime.is_some_and(|rest| rest < 30.0));
+        assert_eq!(session.Exercises[1].RestTime, None);
+    }
+}
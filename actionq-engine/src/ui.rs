@@ -11,7 +11,14 @@ use motion::{
     StateEvent, StateOutput, StateWarning, LuaExercise, Widget
 };
 
+/// How long an error message stays on screen
+const ERROR_DURATION: Duration = Duration::from_secs(10);
+
+/// How long an event of the exercise stays on screen
+const EVENT_DURATION: Duration = Duration::from_secs(3);
+
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     ExerciseStart {
         exercise_id: String,
@@ -20,9 +27,28 @@ pub enum Command {
         state_output: Option<StateOutput>,
         repetitions_target: u32,
         repetitions: u32,
+        /// Score of the last repetition
+        score: Option<f32>,
+        /// Seconds left for the exercises completed by time
+        remaining_time: Option<f32>,
         frame: FrameData,
     },
-    ExerciseEnd
+    ExerciseEnd,
+    Error {
+        message: String,
+    },
+    /// Required joints not visible, empty when the patient is back into the frame
+    MissingJoints {
+        joints: Vec<String>,
+    },
+    /// Rest before the next exercise, with a countdown
+    Rest {
+        duration: Duration,
+        /// Name of the next exercise
+        next: String,
+        /// The countdown is stopped while the session is paused
+        paused: bool,
+    },
 }
 
 #[derive(Debug)]
@@ -33,13 +59,63 @@ impl UiProxy {
         self.0.send(Command::ExerciseStart { exercise_id } ).await.unwrap();
     }
     // Display framedata
-    pub async fn update(&self, state_output: Option<StateOutput>, repetitions_target: u32, repetitions: u32, frame: FrameData) {
-        self.0.send(Command::Update{ state_output, repetitions_target, repetitions, frame }).await.unwrap();
+    pub async fn update(&self, state_output: Option<StateOutput>, repetitions_target: u32, repetitions: u32, score: Option<f32>, remaining_time: Option<f32>, frame: FrameData) {
+        self.0.send(Command::Update{ state_output, repetitions_target, repetitions, score, remaining_time, frame }).await.unwrap();
     }
     // Stop showing exercise
     pub async fn exercise_stop(&self) {
         self.0.send(Command::ExerciseEnd).await.unwrap();
     }
+    // Show an error message for a while
+    pub async fn show_error(&self, message: String) {
+        self.0.send(Command::Error { message }).await.unwrap();
+    }
+    // Ask the patient to move back into the frame, an empty list hides the message
+    pub async fn missing_joints(&self, joints: Vec<String>) {
+        self.0.send(Command::MissingJoints { joints }).await.unwrap();
+    }
+    // Show the rest countdown until the next exercise is shown, stopped if the session is paused
+    pub async fn rest_show(&self, duration: Duration, next: String, paused: bool) {
+        self.0.send(Command::Rest { duration, next, paused }).await.unwrap();
+    }
+}
+
+/// Name of the body part of a joint shown to the patient
+fn body_part_name(joint: &str) -> String {
+    let (side, part) = match joint.split_once('_') {
+        Some((side @ ("left" | "right"), part)) => (Some(side), part),
+        _ => (None, joint),
+    };
+    let (name, feminine) = match part {
+        "nose" => ("naso", false),
+        "eye" => ("occhio", false),
+        "ear" => ("orecchio", false),
+        "shoulder" => ("spalla", true),
+        "elbow" => ("gomito", false),
+        "wrist" => ("polso", false),
+        "hip" => ("anca", true),
+        "knee" => ("ginocchio", false),
+        "ankle" => ("caviglia", true),
+        "neck" => ("collo", false),
+        _ => (part, false),
+    };
+    let side = match (side, feminine) {
+        (Some("left"), false) => "sinistro",
+        (Some("left"), true) => "sinistra",
+        (Some(_), false) => "destro",
+        (Some(_), true) => "destra",
+        (None, _) => return name.to_owned(),
+    };
+    format!("{} {}", name, side)
+}
+
+/// Message shown to the patient for an event, None for the events shown in other ways
+fn event_message(event: &StateEvent) -> Option<String> {
+    match event {
+        StateEvent::Start | StateEvent::Repetition | StateEvent::Complete | StateEvent::Phase { .. } => None,
+        StateEvent::PartialRepetition { progress } => Some(format!("RIPETIZIONE AL {:.0}%", progress * 100.0)),
+        StateEvent::Custom { name, .. } => Some(name.replace('_', " ").to_uppercase()),
+    }
 }
 
 fn non_uniform_columns(ui: &mut Ui) -> Vec<Ui> {
@@ -82,14 +158,39 @@ impl ExerciseGif {
     }
 }
 
+// Countdown of the rest, stopped while the session is paused
+enum RestCountdown {
+    Running(Instant),
+    Paused(Duration),
+}
+
 struct MyUi {
     is_running: bool,
     repetition_count: u32,
+    repetition_score: Option<f32>,
     help_text: Option<String>,
 
+    // Seconds left for the exercises completed by time
+    remaining_time: Option<f32>,
+
     // Widgets to render on top of the video stream
     widgets: Vec<Widget>,
 
+    // Last error message and when it was received
+    error: Option<(String, Instant)>,
+
+    // Body parts the patient must bring back into the frame
+    missing_body_parts: Vec<String>,
+
+    // Current phase of the exercise
+    phase: Option<String>,
+
+    // Last event of the exercise and when it was received
+    event: Option<(String, Instant)>,
+
+    // Countdown of the rest and name of the next exercise
+    rest: Option<(RestCountdown, String)>,
+
     exercise_gif: Option<ExerciseGif>,
     current_frame: Option<egui::ColorImage>,
 
@@ -102,6 +203,12 @@ impl MyUi {
         let _ = egui::TopBottomPanel::top("header").show(ctx, |ui| {
             ui.horizontal_centered(|ui| {
                 ui.heading("ActionQ");
+
+                // Errors are shown for a few seconds
+                if let Some((message, _)) = &self.error {
+                    ui.add_space(25.0);
+                    ui.colored_label(Color32::from_rgb(255, 80, 80), message);
+                }
             });
         });
     }
@@ -198,12 +305,41 @@ impl MyUi {
 
                 // Label for repetitions
                 ui.add_sized([400.0, 100.0], 
-                    egui::Label::new(&format!("RIPETIZIONI: {}", self.repetition_count)));
+                    egui::Label::new(format!("RIPETIZIONI: {}", self.repetition_count)));
+
+                // Label for the time left
+                if let Some(remaining_time) = self.remaining_time {
+                    ui.add_sized([400.0, 50.0], 
+                        egui::Label::new(format!("TEMPO RIMANENTE: {:.0} s", remaining_time.ceil())));
+                }
+
+                // Label for the score of the last repetition
+                if let Some(score) = self.repetition_score {
+                    ui.add_sized([400.0, 50.0], 
+                        egui::Label::new(format!("PUNTEGGIO: {:.0}", score)));
+                }
+
+                // Phase of the exercise and last event
+                if let Some(phase) = &self.phase {
+                    ui.add_sized([400.0, 50.0], 
+                        egui::Label::new(format!("FASE: {}", phase.replace('_', " ").to_uppercase())));
+                }
+                if let Some((message, _)) = &self.event {
+                    ui.colored_label(Color32::from_rgb(80, 200, 120), egui::RichText::new(message).heading());
+                }
+
+                // Ask the patient to move back into the frame
+                if !self.missing_body_parts.is_empty() {
+                    ui.colored_label(
+                        Color32::from_rgb(255, 180, 0),
+                        egui::RichText::new(format!("RIENTRA NELL'INQUADRATURA: {}", self.missing_body_parts.join(", "))).heading()
+                    );
+                }
 
                 // Help text
                 if let Some(help_text) = &self.help_text {
                     // println!("ui render help text: {:?}", help_text);
-                    ui.heading(&format!("[!] {} [!]", help_text));
+                    ui.heading(format!("[!] {} [!]", help_text));
                 }
             }
         });
@@ -231,6 +367,26 @@ impl MyUi {
         });
     }
     
+    fn render_rest(&mut self, ctx: &egui::Context) {
+        let Some((countdown, next)) = &self.rest else {
+            return;
+        };
+        let (remaining, heading) = match countdown {
+            RestCountdown::Running(deadline) => (deadline.saturating_duration_since(Instant::now()), "RIPOSO"),
+            RestCountdown::Paused(remaining) => (*remaining, "RIPOSO IN PAUSA"),
+        };
+        let _ = egui::CentralPanel::default().show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.add_space(100.0);
+                ui.heading(heading);
+                ui.add_space(25.0);
+                ui.label(egui::RichText::new(format!("{}", remaining.as_secs_f32().ceil() as u32)).size(120.0));
+                ui.add_space(25.0);
+                ui.heading(format!("PROSSIMO ESERCIZIO: {}", next));
+            });
+        });
+    }
+
     fn render_viewports(&mut self, ctx: &egui::Context) {
         let _ = egui::CentralPanel::default().show(ctx, |ui| {
             let mut cols = non_uniform_columns(ui);
@@ -251,7 +407,7 @@ impl App for MyUi {
                     tracing::trace!("start exercise display");
                     
                     // Load the gif
-                    let exercise_data = std::fs::read(&format!("/home/nvidia/Repositories/actionq/exercises/{}.webp", exercise_id)).unwrap();
+                    let exercise_data = std::fs::read(format!("/home/nvidia/Repositories/actionq/exercises/{}.webp", exercise_id)).unwrap();
                     let exercise_frames = webp_animation::Decoder::new(&exercise_data).unwrap();
                     let exercise_frames: Vec<egui::ColorImage> = exercise_frames.into_iter()
                         .map(|f| { 
@@ -260,14 +416,16 @@ impl App for MyUi {
                         }).collect();
 
                     self.is_running = true;
+                    self.rest = None;
                     self.repetition_count = 0;
+                    self.repetition_score = None;
                     self.exercise_gif = Some(ExerciseGif {
                         frames: exercise_frames,
                         current_exercise_frame: 0,
                         last_time: Instant::now()
                     });
                 },
-                Command::Update { state_output, repetitions_target, repetitions, frame } => {
+                Command::Update { state_output, repetitions_target: _, repetitions, score, remaining_time, frame } => {
                     tracing::trace!("display single frame");
 
                     let frame_size = frame.framebuffer.size;
@@ -276,6 +434,8 @@ impl App for MyUi {
 
                     // Increase repetition count if necessary
                     self.repetition_count = repetitions;
+                    self.repetition_score = score;
+                    self.remaining_time = remaining_time;
                
                     //println!("ui: {:?}", state_output);
                     if let Some(output) = state_output {
@@ -283,6 +443,16 @@ impl App for MyUi {
                         // Widgets
                         self.widgets = output.metadata.widgets.clone();
 
+                        // Events
+                        for event in &output.metadata.events {
+                            if let StateEvent::Phase { name } = event {
+                                self.phase = Some(name.clone());
+                            }
+                            if let Some(message) = event_message(event) {
+                                self.event = Some((message, Instant::now()));
+                            }
+                        }
+
                         // Help text
                         if let Some(help_text) = output.metadata.help {
                             println!("ui help text: {}", help_text);
@@ -297,16 +467,43 @@ impl App for MyUi {
                     self.exercise_gif = None;
                     self.current_frame = None;
                     self.repetition_count = 0;
+                    self.repetition_score = None;
+                    self.remaining_time = None;
                     self.help_text = None;
                     self.widgets = vec![];
+                    self.missing_body_parts = vec![];
+                    self.phase = None;
+                    self.event = None;
+                    self.rest = None;
+                },
+                Command::Error { message } => {
+                    tracing::trace!("display error");
+                    self.error = Some((message, Instant::now()));
+                },
+                Command::MissingJoints { joints } => {
+                    tracing::trace!("display missing joints");
+                    self.missing_body_parts = joints.iter().map(|j| body_part_name(j)).collect();
+                },
+                Command::Rest { duration, next, paused } => {
+                    tracing::trace!("display rest countdown");
+                    let countdown = if paused { RestCountdown::Paused(duration) } else { RestCountdown::Running(Instant::now() + duration) };
+                    self.rest = Some((countdown, next));
                 },
-                _ => {}
             }
         }
 
+        if self.error.as_ref().is_some_and(|(_, since)| since.elapsed() >= ERROR_DURATION) {
+            self.error = None;
+        }
+        if self.event.as_ref().is_some_and(|(_, since)| since.elapsed() >= EVENT_DURATION) {
+            self.event = None;
+        }
+
         self.render_top_menu_bar(ctx);
         if self.is_running {
             self.render_viewports(ctx);
+        } else {
+            self.render_rest(ctx);
         }
 
         // Request update of the ui, we always want this
@@ -332,10 +529,17 @@ pub fn run_ui_blocking(rx: Receiver<Command>) {
             Ok(Box::new(MyUi {
                 is_running: false,
                 repetition_count: 0,
+                repetition_score: None,
+                remaining_time: None,
                 cmds: rx,
                 exercise_gif: None,
                 current_frame: None,
                 widgets: vec![],
+                error: None,
+                missing_body_parts: vec![],
+                phase: None,
+                event: None,
+                rest: None,
                 help_text: None
             }))
         }),
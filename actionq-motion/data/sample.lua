@@ -1,10 +1,51 @@
 -- In what landmarks are we interestend in? 
 -- If they are not present the script will not run in the current frame.
-JOINTS = { "shoulder", "elbow", "wrist" }
+JOINTS = { "right_shoulder", "right_elbow", "right_wrist" }
 
 -- All states of the system except the start one
 STATES = { "down", "up" }
 
+-- Each state function receives the skeleton, the table of the control factors
+-- computed on the current frame, for example factors.arm_inner_angle_l,
+-- and the frame timing: frame.time and frame.dt in seconds, frame.index
+--
+-- Time helpers:
+--   state_time()                  seconds since the current state is active
+--   hold(name, condition, secs)   true once the named condition is satisfied for secs seconds,
+--                                 also returns for how long it has been satisfied
+--
+-- Geometry helpers, vectors are tables {x, y} in image coordinates (y grows downward):
+--   UP, DOWN, LEFT, RIGHT                    unit directions
+--   vec2(x, y), vec_add(a, b), vec_sub(a, b), vec_scale(a, k), vec_dot(a, b),
+--   vec_length(a), vec_normalize(a)
+--   distance(a, b), midpoint(a, b)
+--   angle_to_vertical(a, b)                  angle of the segment a->b with the vertical axis,
+--                                            from 0 with b below a to 180 with b above a
+--   angle_to_horizontal(a, b)                angle of the segment a->b with the horizontal axis,
+--                                            from -90 with b below a to 90 with b above a
+--   torso_length(skeleton)                   neck to middle of the hips, nil if the joints are missing
+--   normalized_distance(skeleton, a, b)      distance in torso lengths
+--   symmetry(skeleton, a, z, b)              difference between the left and right angle at z,
+--                                            for example symmetry(skeleton, "shoulder", "elbow", "wrist")
+--
+-- History helpers, over the last HISTORY_FRAMES skeletons (default 90), joints are names:
+--   joint_ago(joint, n)                      position n frames ago, 0 is the current frame
+--   velocity(joint), speed(joint)            movement since the previous frame, per second
+--   acceleration(joint)                      change of velocity over the last three frames
+--   still(joint, secs, tolerance)            true if the joint moved less than tolerance for secs seconds
+-- They return nil (still returns false) until enough frames are available
+--
+-- confidence(joint)                          confidence of the pose estimator for the joint in the current frame,
+--                                            between 0 and 1, nil if the joint is missing
+--
+-- Events, in the 'events' list of the metadata, are names or tables with a name:
+--   "start", "repetition"                    start of the exercise and completed repetition
+--   "half_repetition"                        part of a repetition, or { name = "partial_repetition", progress = 0.25 }
+--   "phase:<name>"                           the exercise moved to another phase, or { name = "phase", phase = "<name>" }
+--   "complete"                               the exercise is complete, when the session completes it by event
+--   any other name                           custom event, with optional numbers { name = "side_switch", payload = { side = 1 } }
+-- They are shown on the UI and stored with the session
+
 --------------------------------------------------------------------------------------------
 -- UTILITIES
 
@@ -28,18 +69,54 @@ end
 --------------------------------------------------------------------------------------------
 -- EXERCISE
 
-WORK_ANGLE_THRESHOLD = 110.0
+-- Optional, parameters of the exercise with their defaults (numbers, strings or booleans).
+-- A session can change them, the values are read from PARAMS once the script is loaded,
+-- for example PARAMS.work_angle. At the top level of the script PARAMS has the defaults
+PARAMETERS = {
+	work_angle = 110.0,
+}
+
+-- Optional, how much each warning lowers the score of a repetition (default 1.0).
+-- A warning with weight 1.0 present during the whole repetition brings the score from 100 to 0
+WARNING_WEIGHTS = {
+	arms_not_sync = 0.5,
+}
+
+-- Optional, how many previous skeletons are kept for the history helpers
+HISTORY_FRAMES = 60
+
+-- Invocato una volta prima dell'esecuzione dell'esercizio, context contiene name, description,
+-- repetitions_target, repetitions e parameters (i valori di PARAMS)
+function setup(context)
+	peak_angle = 0.0
+end
 
--- Invocato prima dell'esecuzione dell'esercizio
-function setup() end
+-- Optional lifecycle hooks:
+--   teardown(context)                 once when the exercise ends, completed or interrupted
+--   on_repetition(repetitions, frame) after each repetition
+--   on_pause(), on_resume()           when the session is paused and resumed
+--   ON_ENTER.<state>(frame, previous) when the state becomes active, for entry with the first frame
+--   ON_EXIT.<state>(frame, next)      when the state is left
+ON_ENTER = {
+	up = function(frame, previous)
+		peak_angle = 0.0
+	end,
+}
+
+-- Massimo angolo raggiunto nelle ripetizioni completate
+best_angle = 0.0
+
+function on_repetition(repetitions, frame)
+	best_angle = math.max(best_angle, peak_angle)
+end
 
 -- It is useful to create a generic warning function for all states
 function warnings(skeleton)
 	results = {}
 
 	-- Controlla se braccia sono piegato in modo simmetrico
-	angle_a = inner_angle(skeleton.shoulder, skeleton.elbow, skeleton.wrist)
-	angle_b = 180.0 - inner_angle(skeleton.shoulder, skeleton.elbow, skeleton.wrist)
+	angle_a = inner_angle(skeleton.right_shoulder, skeleton.right_elbow, skeleton.right_wrist)
+	angle_b = 180.0 - inner_angle(skeleton.right_shoulder, skeleton.right_elbow, skeleton.right_wrist)
 
 	if not near(angle_a, 15.0, angle_b) then
 		table.insert(results, {
@@ -58,7 +135,7 @@ end
 -- iniziale corretta.
 function entry(skeleton)
 	-- Valore guida per questo esercizio
-	work = inner_angle_aligned(skeleton.shoulder, skeleton.elbow, skeleton.wrist)
+	work = inner_angle_aligned(skeleton.right_shoulder, skeleton.right_elbow, skeleton.right_wrist)
 
 	-- Cambia stato
 	if near(0.0, 15.0, work) then
@@ -66,7 +143,7 @@ function entry(skeleton)
 			-- Avvisi condivisi da tutti gli stati
 			warnings = warnings(skeleton),
 			-- Oltre a cambiare stato informiamo che possiamo iniziare l'esercizio vero e proprio
-			events = { "start" },
+			events = { "start", "phase:raising" },
 		})
 	end
 
@@ -76,17 +153,18 @@ function entry(skeleton)
 		delta = {
 			-- Quanto manca a raggiungere la posizione di riposo
 			angle_to_base = work,
+			peak_angle = peak_angle,
 		},
 	})
 end
 
 -- Example state
 function down(skeleton)
-	work = inner_angle_aligned(skeleton.shoulder, skeleton.elbow, skeleton.wrist)
-	if work >= WORK_ANGLE_THRESHOLD then
+	work = inner_angle_aligned(skeleton.right_shoulder, skeleton.right_elbow, skeleton.right_wrist)
+	if work >= PARAMS.work_angle then
 		return step("up", {
 			-- Oltre a cambiare stato informiamo il sistema che abbiamo eseguito una ripetizione
-			events = { "repetition" },
+			events = { "repetition", "phase:lowering" },
 		})
 	end
 
@@ -94,17 +172,18 @@ function down(skeleton)
 		warnings = warnings(skeleton),
 		delta = {
 			-- Quanto manca a raggiungere la soglia di cambio stato
-			angle_to_threshold = WORK_ANGLE_THRESHOLD - work,
+			angle_to_threshold = PARAMS.work_angle - work,
 		},
 	})
 end
 
-function up(value)
-	work = inner_angle_aligned(skeleton.shoulder, skeleton.elbow, skeleton.wrist)
+function up(skeleton)
+	work = inner_angle_aligned(skeleton.right_shoulder, skeleton.right_elbow, skeleton.right_wrist)
+	peak_angle = math.max(peak_angle, work)
 	if near(0.0, 15.0, work) then
 		return step("down", {
 			warnings = warnings(skeleton),
-			events = { "repetition" },
+			events = { "repetition", "phase:raising" },
 		})
 	end
 
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+
+use crate::{filter::median, StateEvent};
+
+/// Intervals between two evaluated frames longer than this many typical intervals are not counted
+/// as exercise time, the patient was out of the frame or the session was paused
+const MAX_GAP_INTERVALS: f32 = 4.0;
+
+/// Number of recent intervals between the frames used to find the typical interval of the source
+const RECENT_INTERVALS: usize = 15;
+
+/// When an exercise is complete
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CompletionCriteria {
+    /// After the target number of repetitions
+    #[default]
+    Repetitions,
+    /// After the exercise was evaluated for the given seconds, for example a plank
+    ActiveTime { seconds: f32 },
+    /// After the given seconds spent in the state, in total, for example holding a stretch
+    TimeInState { state: String, seconds: f32 },
+    /// When the exercise emits the 'complete' event
+    Event,
+}
+
+impl CompletionCriteria {
+    /// State whose time is counted, if any
+    pub fn state(&self) -> Option<&str> {
+        match self {
+            Self::TimeInState { state, .. } => Some(state),
+            _ => None,
+        }
+    }
+}
+
+/// Tracks the progress of an exercise towards its completion criteria
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    criteria: CompletionCriteria,
+    /// Capture time of the last evaluated frame
+    last_time: Option<f32>,
+    /// Recent intervals between the evaluated frames, their median follows the frame rate of the source
+    intervals: VecDeque<f32>,
+    /// Seconds during which the exercise was evaluated
+    active_time: f32,
+    /// Seconds spent in the state of the criteria
+    state_time: f32,
+    /// The exercise emitted the 'complete' event
+    completed_by_event: bool,
+}
+
+impl Completion {
+
+    pub fn new(criteria: CompletionCriteria) -> Self {
+        Self { criteria, ..Default::default() }
+    }
+
+    pub fn criteria(&self) -> &CompletionCriteria {
+        &self.criteria
+    }
+
+    /// Seconds during which the exercise was evaluated
+    pub fn active_time(&self) -> f32 {
+        self.active_time
+    }
+
+    /// Account an evaluated frame, the state is the one that evaluated the frame
+    /// and the events are the ones emitted in the frame
+    pub fn update(&mut self, time: f32, state: &str, events: &[StateEvent]) {
+        let dt = self.last_time.map_or(0.0, |last| time - last);
+        self.last_time = Some(time);
+
+        if dt > 0.0 {
+            // The first interval is not counted, the frame rate of the source is still unknown
+            let counted = self.typical_interval().is_some_and(|interval| dt <= interval * MAX_GAP_INTERVALS);
+            if self.intervals.len() == RECENT_INTERVALS {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(dt);
+
+            if counted {
+                self.active_time += dt;
+                if self.criteria.state() == Some(state) {
+                    self.state_time += dt;
+                }
+            }
+        }
+        self.completed_by_event |= events.contains(&StateEvent::Complete);
+    }
+
+    /// Median of the recent intervals between the evaluated frames
+    fn typical_interval(&self) -> Option<f32> {
+        (!self.intervals.is_empty()).then(|| median(self.intervals.iter().copied().collect()))
+    }
+
+    /// True if the exercise reached its criteria, the repetitions are used by the repetition count
+    pub fn is_complete(&self, repetitions: u32, repetitions_target: u32) -> bool {
+        match &self.criteria {
+            CompletionCriteria::Repetitions => repetitions >= repetitions_target,
+            CompletionCriteria::ActiveTime { seconds } => self.active_time >= *seconds,
+            CompletionCriteria::TimeInState { seconds, .. } => self.state_time >= *seconds,
+            CompletionCriteria::Event => self.completed_by_event,
+        }
+    }
+
+    /// Seconds left for the criteria based on time, None for the other criteria
+    pub fn remaining_time(&self) -> Option<f32> {
+        match &self.criteria {
+            CompletionCriteria::ActiveTime { seconds } => Some((seconds - self.active_time).max(0.0)),
+            CompletionCriteria::TimeInState { seconds, .. } => Some((seconds - self.state_time).max(0.0)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    /// Evaluate the frames at the given rate from the start time, all in the same state
+    fn run(completion: &mut Completion, start: f32, frames: usize, fps: f32, state: &str) {
+        for i in 0..frames {
+            completion.update(start + i as f32 / fps, state, &[]);
+        }
+    }
+
+    #[test]
+    fn repetitions_complete_at_the_target() {
+        let completion = Completion::new(CompletionCriteria::Repetitions);
+        assert!(!completion.is_complete(9, 10));
+        assert!(completion.is_complete(10, 10));
+        assert_eq!(completion.remaining_time(), None);
+    }
+
+    #[test]
+    fn active_time_ignores_the_repetitions() {
+        let mut completion = Completion::new(CompletionCriteria::ActiveTime { seconds: 2.0 });
+        run(&mut completion, 0.0, 31, 10.0, "hold");
+        assert!((completion.active_time() - 2.9).abs() < EPSILON);
+        assert!(completion.is_complete(0, 10));
+        assert_eq!(completion.remaining_time(), Some(0.0));
+
+        let mut completion = Completion::new(CompletionCriteria::ActiveTime { seconds: 2.0 });
+        run(&mut completion, 0.0, 11, 10.0, "hold");
+        assert!(!completion.is_complete(10, 10));
+        assert!((completion.remaining_time().unwrap() - 1.1).abs() < EPSILON);
+    }
+
+    #[test]
+    fn time_in_state_counts_only_the_state() {
+        let criteria = CompletionCriteria::TimeInState { state: "hold".to_owned(), seconds: 1.0 };
+        let mut completion = Completion::new(criteria);
+        run(&mut completion, 0.0, 10, 10.0, "rest");
+        run(&mut completion, 1.0, 9, 10.0, "hold");
+        assert!(!completion.is_complete(0, 0));
+        assert!((completion.remaining_time().unwrap() - 0.1).abs() < EPSILON);
+        run(&mut completion, 1.9, 2, 10.0, "hold");
+        assert!(completion.is_complete(0, 0));
+        assert!((completion.active_time() - 1.9).abs() < EPSILON);
+    }
+
+    #[test]
+    fn event_completes_the_exercise() {
+        let mut completion = Completion::new(CompletionCriteria::Event);
+        completion.update(0.0, "entry", &[StateEvent::Repetition]);
+        assert!(!completion.is_complete(1, 1));
+        completion.update(0.1, "entry", &[StateEvent::Complete]);
+        assert!(completion.is_complete(0, 1));
+    }
+
+    #[test]
+    fn pauses_are_not_counted() {
+        let mut completion = Completion::new(CompletionCriteria::ActiveTime { seconds: 10.0 });
+        run(&mut completion, 0.0, 11, 10.0, "hold");
+        run(&mut completion, 5.0, 11, 10.0, "hold");
+        assert!((completion.active_time() - 1.9).abs() < EPSILON);
+    }
+
+    #[test]
+    fn slow_sources_are_counted() {
+        let mut completion = Completion::new(CompletionCriteria::ActiveTime { seconds: 10.0 });
+        run(&mut completion, 0.0, 11, 1.0, "hold");
+        assert!((completion.active_time() - 9.0).abs() < EPSILON);
+        run(&mut completion, 30.0, 2, 1.0, "hold");
+        assert!((completion.active_time() - 10.0).abs() < EPSILON);
+        assert!(completion.is_complete(0, 0));
+    }
+}
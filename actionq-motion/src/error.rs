@@ -0,0 +1,52 @@
+use mlua::prelude::*;
+
+/// Value returned by a state function that cannot be converted
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("invalid value {value} for field '{field}', expected {expected}")]
+pub struct InvalidValue {
+    /// Path of the field inside the state output, for example "metadata.widgets[2].widget"
+    pub field: String,
+    /// The wrong value as written by the script
+    pub value: String,
+    /// Description of the accepted values
+    pub expected: String,
+}
+
+/// Errors of the exercises
+#[derive(Debug, thiserror::Error)]
+pub enum MotionError {
+    #[error("unable to read script: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The script cannot be loaded or lacks one of the required globals
+    #[error("unable to load script of exercise '{exercise}': {source}")]
+    Load { exercise: String, source: Box<LuaError> },
+
+    /// A parameter of the session is not accepted by the script
+    #[error("exercise '{exercise}': {source}")]
+    InvalidParameter { exercise: String, source: Box<crate::ParameterError> },
+
+    /// The completion criteria counts the time of a state the exercise does not have
+    #[error("exercise '{exercise}': unknown state '{state}' in the completion criteria")]
+    UnknownCompletionState { exercise: String, state: String },
+
+    /// The state function raised an error
+    #[error("exercise '{exercise}', state '{state}': {source}")]
+    Runtime { exercise: String, state: String, source: Box<LuaError> },
+
+    /// A lifecycle hook of the script raised an error
+    #[error("exercise '{exercise}', hook '{hook}': {source}")]
+    Hook { exercise: String, hook: String, source: Box<LuaError> },
+
+    /// The state function returned a value that is not a valid state output
+    #[error("exercise '{exercise}', state '{state}': {source}")]
+    InvalidOutput { exercise: String, state: String, source: Box<InvalidValue> },
+
+    /// The current state is not defined by the exercise, it was changed from outside
+    #[error("exercise '{exercise}': the current state '{state}' is not defined")]
+    InvalidCurrentState { exercise: String, state: String },
+
+    /// The state function moved to a state without a function
+    #[error("exercise '{exercise}', state '{state}': unknown next state '{next_state}'")]
+    UnknownState { exercise: String, state: String, next_state: String },
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use glam::Vec2;
+
+use crate::Skeleton;
+use crate::geometry::{angle_to_horizontal, angle_to_vertical, inner_angle, joint, middle};
+
+/// Values of the control factors computed on a single frame
+pub type ControlFactors = HashMap<String, f32>;
+
+/// Compute a control factor from the skeleton, returns None if the required joints are missing
+pub type ControlFactorFn = Box<dyn Fn(&Skeleton) -> Option<f32> + Send + Sync>;
+
+/// Named value computed from the skeleton, used by the exercises to evaluate the patient's posture
+pub struct ControlFactor {
+    /// What the control factor measures and in what unit
+    pub description: String,
+    compute: ControlFactorFn,
+}
+
+impl std::fmt::Debug for ControlFactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ControlFactor")
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+/// Collection of all the control factors available to the exercises
+#[derive(Debug)]
+pub struct ControlFactorRegistry {
+    factors: BTreeMap<String, ControlFactor>,
+}
+
+impl ControlFactorRegistry {
+
+    /// Create a registry without any control factor
+    pub fn empty() -> Self {
+        Self { factors: BTreeMap::new() }
+    }
+
+    /// Add a control factor, replaces any control factor with the same name
+    pub fn register<F>(&mut self, name: &str, description: &str, compute: F)
+    where F: Fn(&Skeleton) -> Option<f32> + Send + Sync + 'static {
+        self.factors.insert(name.to_owned(), ControlFactor {
+            description: description.to_owned(),
+            compute: Box::new(compute),
+        });
+    }
+
+    /// Add the inner angle at the joint z, for both sides of the body
+    fn register_angle(&mut self, name: &str, description: &str, joints: [&'static str; 3]) {
+        for (suffix, side) in [("l", "left"), ("r", "right")] {
+            let [a, z, b] = joints.map(|j| format!("{side}_{j}"));
+            self.register(&format!("{name}_{suffix}"), &format!("{description} ({side}), degrees"), move |s| {
+                Some(inner_angle(joint(s, &a)?, joint(s, &z)?, joint(s, &b)?))
+            });
+        }
+    }
+
+    /// Add the angle of the segment a->b computed by the function, for both sides of the body
+    fn register_axis_angle(&mut self, name: &str, description: &str, angle: fn(Vec2, Vec2) -> f32, joints: [&'static str; 2]) {
+        for (suffix, side) in [("l", "left"), ("r", "right")] {
+            let [a, b] = joints.map(|j| format!("{side}_{j}"));
+            self.register(&format!("{name}_{suffix}"), &format!("{description} ({side}), degrees"), move |s| {
+                Some(angle(joint(s, &a)?, joint(s, &b)?))
+            });
+        }
+    }
+
+    /// Add the distance between the left and right joints, along the axis if present
+    fn register_distance(&mut self, name: &str, description: &str, axis: Option<Vec2>, joint_name: &'static str) {
+        let (l, r) = (format!("left_{joint_name}"), format!("right_{joint_name}"));
+        self.register(name, &format!("{description}, pixels"), move |s| {
+            let d = joint(s, &l)? - joint(s, &r)?;
+            Some(match axis {
+                Some(axis) => d.dot(axis).abs(),
+                None => d.length()
+            })
+        });
+    }
+
+    /// Check if a control factor exists
+    pub fn contains(&self, name: &str) -> bool {
+        self.factors.contains_key(name)
+    }
+
+    /// Names and descriptions of all the control factors
+    pub fn descriptions(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.factors.iter().map(|(k, v)| (k.as_str(), v.description.as_str()))
+    }
+
+    /// Compute a single control factor, None if it is unknown or the required joints are missing
+    pub fn compute_one(&self, name: &str, skeleton: &Skeleton) -> Option<f32> {
+        self.factors.get(name).and_then(|f| (f.compute)(skeleton))
+    }
+
+    /// Compute all the control factors available with the joints of the skeleton
+    pub fn compute(&self, skeleton: &Skeleton) -> ControlFactors {
+        self.factors.iter()
+            .filter_map(|(name, f)| Some((name.clone(), (f.compute)(skeleton)?)))
+            .collect()
+    }
+}
+
+/// Registry with all the builtin control factors
+impl Default for ControlFactorRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+
+        // Joint angles
+        registry.register_angle("arm_angle", "Angle between the torso and the upper arm", ["hip", "shoulder", "elbow"]);
+        registry.register_angle("arm_inner_angle", "Angle of the elbow", ["shoulder", "elbow", "wrist"]);
+        registry.register_angle("hip_angle", "Angle between the torso and the thigh", ["shoulder", "hip", "knee"]);
+        registry.register_angle("leg_inner_angle", "Angle of the knee", ["hip", "knee", "ankle"]);
+
+        // Angles compared to an axis
+        registry.register_axis_angle("arm_horiz_angle", "Angle between the whole arm and the horizontal axis, -90 lowered to 90 raised", angle_to_horizontal, ["shoulder", "wrist"]);
+        registry.register_axis_angle("arm_vert_angle", "Angle between the whole arm and the vertical axis, 0 lowered to 180 raised", angle_to_vertical, ["shoulder", "wrist"]);
+        registry.register_axis_angle("thigh_vert_angle", "Angle between the thigh and the vertical axis, 0 standing", angle_to_vertical, ["hip", "knee"]);
+        registry.register("torso_vert_angle", "Angle between the torso and the vertical axis, 0 upright, degrees", |s| {
+            Some(angle_to_vertical(joint(s, "neck")?, middle(s, "hip")?))
+        });
+        registry.register("angle_column_femor", "Angle at the hips between the spine and the femurs, degrees", |s| {
+            Some(inner_angle(joint(s, "neck")?, middle(s, "hip")?, middle(s, "knee")?))
+        });
+
+        // Distances
+        registry.register_distance("feet_distance", "Distance between the ankles", None, "ankle");
+        registry.register_distance("feet_distance_x", "Horizontal distance between the ankles", Some(Vec2::X), "ankle");
+        registry.register_distance("knees_distance_x", "Horizontal distance between the knees", Some(Vec2::X), "knee");
+        registry.register_distance("wrists_distance", "Distance between the wrists", None, "wrist");
+        registry.register_distance("wrists_distance_y", "Vertical distance between the wrists", Some(Vec2::Y), "wrist");
+        registry.register_distance("shoulders_distance_y", "Vertical distance between the shoulders", Some(Vec2::Y), "shoulder");
+
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    /// Left arm straight at the angle from the torso, in degrees
+    fn left_arm_at(angle: f32) -> Skeleton {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        [("left_shoulder", Vec2::new(60.0, 20.0)), ("left_wrist", Vec2::new(60.0 + 40.0 * sin, 20.0 + 40.0 * cos))]
+            .into_iter().map(|(name, position)| (name.to_owned(), position.into())).collect()
+    }
+
+    #[test]
+    fn arm_angles_distinguish_raised_and_lowered_arms() {
+        let registry = ControlFactorRegistry::default();
+        for (angle, vertical, horizontal) in [(30.0, 30.0, -60.0), (90.0, 90.0, 0.0), (150.0, 150.0, 60.0)] {
+            let skeleton = left_arm_at(angle);
+            assert!((registry.compute_one("arm_vert_angle_l", &skeleton).unwrap() - vertical).abs() < EPSILON);
+            assert!((registry.compute_one("arm_horiz_angle_l", &skeleton).unwrap() - horizontal).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn missing_joints_have_no_value() {
+        let registry = ControlFactorRegistry::default();
+        let factors = registry.compute(&left_arm_at(30.0));
+        assert!(factors.contains_key("arm_vert_angle_l"));
+        assert!(!factors.contains_key("arm_vert_angle_r"));
+        assert_eq!(registry.compute_one("torso_vert_angle", &left_arm_at(30.0)), None);
+    }
+}
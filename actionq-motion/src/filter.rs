@@ -0,0 +1,260 @@
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+use std::str::FromStr;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{Joint, Skeleton};
+
+/// Smoothing applied to the keypoints before the exercise evaluates them.
+/// The pose estimator jitters from frame to frame, which can cross a threshold twice
+/// and count a repetition more than once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterConfig {
+    /// Keypoints are used as received
+    #[default]
+    None,
+    /// One Euro filter, a low-pass filter whose cutoff frequency grows with the speed of the joint,
+    /// smooths the joints at rest while following the fast movements
+    OneEuro {
+        /// Cutoff frequency in Hz of a joint at rest, lower values remove more jitter
+        #[serde(default = "default_min_cutoff")]
+        min_cutoff: f32,
+        /// How much the cutoff frequency grows with the speed, higher values reduce the lag
+        #[serde(default = "default_beta")]
+        beta: f32,
+        /// Cutoff frequency in Hz used to smooth the speed
+        #[serde(default = "default_derivative_cutoff")]
+        derivative_cutoff: f32,
+    },
+    /// Exponential moving average
+    Ema {
+        /// Weight of the new keypoint, between 0 and 1, lower values remove more jitter
+        #[serde(default = "default_alpha")]
+        alpha: f32,
+    },
+    /// Median of each coordinate over the last frames, removes the isolated outliers
+    Median {
+        /// Number of frames
+        #[serde(default = "default_window")]
+        window: usize,
+    },
+}
+
+fn default_min_cutoff() -> f32 { 1.0 }
+fn default_beta() -> f32 { 0.01 }
+fn default_derivative_cutoff() -> f32 { 1.0 }
+fn default_alpha() -> f32 { 0.5 }
+fn default_window() -> usize { 5 }
+
+/// Parse the name of a filter with its default parameters: none, one_euro, ema or median
+impl FromStr for FilterConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "one_euro" => Ok(Self::OneEuro {
+                min_cutoff: default_min_cutoff(),
+                beta: default_beta(),
+                derivative_cutoff: default_derivative_cutoff(),
+            }),
+            "ema" => Ok(Self::Ema { alpha: default_alpha() }),
+            "median" => Ok(Self::Median { window: default_window() }),
+            _ => Err(format!("unknown filter: {}", s)),
+        }
+    }
+}
+
+/// Smoothing factor of a low-pass filter with the given cutoff frequency and time step
+fn smoothing_factor(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+/// Median of the values, the list must not be empty
+pub(crate) fn median(mut values: Vec<f32>) -> f32 {
+    values.sort_unstable_by(f32::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// State of the filter of a single joint
+#[derive(Debug, Clone)]
+enum JointState {
+    OneEuro { time: f32, position: Vec2, velocity: Vec2 },
+    Ema { position: Vec2 },
+    Median { positions: VecDeque<Vec2> },
+}
+
+/// Filter of the skeletons of a single subject, the state of each joint is kept between frames
+#[derive(Debug, Clone, Default)]
+pub struct SkeletonFilter {
+    config: FilterConfig,
+    joints: HashMap<String, JointState>,
+}
+
+impl SkeletonFilter {
+
+    /// Create a filter without any previous frame
+    pub fn new(config: FilterConfig) -> Self {
+        Self { config, joints: HashMap::new() }
+    }
+
+    /// Filter used by this instance
+    pub fn config(&self) -> FilterConfig {
+        self.config
+    }
+
+    /// True if the skeletons are returned unchanged
+    pub fn is_passthrough(&self) -> bool {
+        self.config == FilterConfig::None
+    }
+
+    /// Forget all the previous frames
+    pub fn reset(&mut self) {
+        self.joints.clear();
+    }
+
+    /// Filter the positions of the skeleton captured at the given time in seconds, the confidence is unchanged.
+    /// A joint missing from a frame restarts its filter, so it is never smoothed across a gap
+    pub fn apply(&mut self, skeleton: &Skeleton, time: f32) -> Skeleton {
+        if self.is_passthrough() {
+            return skeleton.clone();
+        }
+
+        self.joints.retain(|name, _| skeleton.contains_key(name));
+        skeleton.iter()
+            .map(|(name, joint)| {
+                let position = match self.joints.get_mut(name) {
+                    Some(state) => Self::update(&self.config, state, joint.position, time),
+                    None => {
+                        self.joints.insert(name.clone(), Self::initial(&self.config, joint.position, time));
+                        joint.position
+                    }
+                };
+                (name.clone(), Joint { position, ..*joint })
+            })
+            .collect()
+    }
+
+    /// State of a joint observed for the first time
+    fn initial(config: &FilterConfig, position: Vec2, time: f32) -> JointState {
+        match config {
+            FilterConfig::None | FilterConfig::Ema { .. } => JointState::Ema { position },
+            FilterConfig::OneEuro { .. } => JointState::OneEuro { time, position, velocity: Vec2::ZERO },
+            FilterConfig::Median { .. } => JointState::Median { positions: VecDeque::from([position]) },
+        }
+    }
+
+    /// Add a new position of a joint, returns the filtered position
+    fn update(config: &FilterConfig, state: &mut JointState, position: Vec2, now: f32) -> Vec2 {
+        match (config, state) {
+            (FilterConfig::OneEuro { min_cutoff, beta, derivative_cutoff }, JointState::OneEuro { time, position: previous, velocity }) => {
+                let dt = now - *time;
+                if dt <= 0.0 {
+                    return *previous;
+                }
+
+                let raw_velocity = (position - *previous) / dt;
+                *velocity = velocity.lerp(raw_velocity, smoothing_factor(*derivative_cutoff, dt));
+                let cutoff = min_cutoff + beta * velocity.length();
+                *previous = previous.lerp(position, smoothing_factor(cutoff, dt));
+                *time = now;
+                *previous
+            },
+            (FilterConfig::Ema { alpha }, JointState::Ema { position: previous }) => {
+                *previous = previous.lerp(position, alpha.clamp(0.0, 1.0));
+                *previous
+            },
+            (FilterConfig::Median { window }, JointState::Median { positions }) => {
+                if positions.len() >= (*window).max(1) {
+                    positions.pop_front();
+                }
+                positions.push_back(position);
+                Vec2::new(
+                    median(positions.iter().map(|p| p.x).collect()),
+                    median(positions.iter().map(|p| p.y).collect())
+                )
+            },
+            _ => position,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn skeleton(x: f32) -> Skeleton {
+        HashMap::from([("left_wrist".to_owned(), Joint::new(Vec2::new(x, 0.0), 0.7))])
+    }
+
+    /// Filter the positions along x at 30 frames per second, returns the filtered positions
+    fn run(filter: &mut SkeletonFilter, positions: &[f32]) -> Vec<f32> {
+        positions.iter().enumerate()
+            .map(|(i, x)| filter.apply(&skeleton(*x), i as f32 / 30.0)["left_wrist"].position.x)
+            .collect()
+    }
+
+    #[test]
+    fn none_returns_the_keypoints() {
+        let mut filter = SkeletonFilter::new(FilterConfig::None);
+        assert_eq!(run(&mut filter, &[0.0, 10.0, -5.0]), [0.0, 10.0, -5.0]);
+    }
+
+    #[test]
+    fn ema_moves_towards_the_new_keypoint() {
+        let mut filter = SkeletonFilter::new(FilterConfig::Ema { alpha: 0.5 });
+        assert_eq!(run(&mut filter, &[0.0, 10.0, 10.0]), [0.0, 5.0, 7.5]);
+    }
+
+    #[test]
+    fn median_removes_the_outliers() {
+        let mut filter = SkeletonFilter::new(FilterConfig::Median { window: 3 });
+        assert_eq!(run(&mut filter, &[0.0, 1.0, 100.0, 2.0, 3.0]), [0.0, 0.5, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn one_euro_reduces_the_jitter_at_rest() {
+        let mut filter = SkeletonFilter::new("one_euro".parse().unwrap());
+        let jitter: Vec<f32> = (0..60).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }).collect();
+        let filtered = run(&mut filter, &jitter);
+        assert!(filtered[30..].iter().all(|x| x.abs() < 0.5), "{:?}", filtered);
+    }
+
+    #[test]
+    fn one_euro_follows_the_movements() {
+        let mut filter = SkeletonFilter::new("one_euro".parse().unwrap());
+        let movement: Vec<f32> = (0..90).map(|i| if i < 30 { 0.0 } else { 200.0 }).collect();
+        let filtered = run(&mut filter, &movement);
+        assert!((filtered[89] - 200.0).abs() < 1.0, "{:?}", filtered);
+    }
+
+    #[test]
+    fn missing_joints_restart_the_filter() {
+        let mut filter = SkeletonFilter::new(FilterConfig::Ema { alpha: 0.5 });
+        run(&mut filter, &[0.0, 0.0]);
+        filter.apply(&Skeleton::new(), 0.1);
+        let joint = filter.apply(&skeleton(10.0), 0.2)["left_wrist"];
+        assert!((joint.position.x - 10.0).abs() < EPSILON);
+        assert_eq!(joint.confidence, 0.7);
+    }
+
+    #[test]
+    fn filters_are_parsed_with_their_defaults() {
+        assert_eq!("ema".parse(), Ok(FilterConfig::Ema { alpha: default_alpha() }));
+        assert_eq!("median".parse(), Ok(FilterConfig::Median { window: default_window() }));
+        assert!("kalman".parse::<FilterConfig>().is_err());
+
+        let config: FilterConfig = serde_json::from_str(r#"{"type": "one_euro", "beta": 0.5}"#).unwrap();
+        assert_eq!(config, FilterConfig::OneEuro { min_cutoff: 1.0, beta: 0.5, derivative_cutoff: 1.0 });
+    }
+}
@@ -0,0 +1,566 @@
+use std::{collections::HashMap, ops::Range, path::Path, sync::Arc};
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::{Completion, CompletionCriteria, ControlFactorRegistry, ControlFactors, Exercise, Frame, Metadata, MotionError, Preprocessing, RepetitionLog, Scoring, Skeleton, StateEvent, StateOutput, StateWarning};
+
+/// Errors generated while loading a declarative exercise
+#[derive(Debug, thiserror::Error)]
+pub enum FsmError {
+    #[error("unable to read exercise: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed exercise: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("malformed exercise: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("state '{state}' references the undefined state '{target}'")]
+    UnknownState { state: String, target: String },
+    #[error("the initial state '{0}' is not defined")]
+    UnknownInitialState(String),
+    #[error("state '{state}' emits the event '{event}' without a name, use 'phase:<name>'")]
+    UnnamedPhase { state: String, event: String },
+    #[error("unknown joint '{0}'")]
+    UnknownJoint(String),
+    #[error("unknown control factor '{0}'")]
+    UnknownControlFactor(String),
+    #[error("control factor '{0}' is not listed in the required control factors")]
+    UnlistedControlFactor(String),
+}
+
+/// Condition applied to the value of a control factor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConditionKind {
+    /// The value must be inside the range
+    InRange { range: Range<f32> },
+    /// The value must be outside the range
+    NotInRange { range: Range<f32> },
+    /// The value must be greater than the threshold
+    GreaterThan { value: f32 },
+    /// The value must be less than the threshold
+    LessThan { value: f32 },
+}
+
+/// Condition on a single control factor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Condition {
+    /// Name of the control factor to check
+    pub control_factor: String,
+    pub condition: ConditionKind,
+}
+
+/// Transition to another state, taken when all the conditions are satisfied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    pub conditions: Vec<Condition>,
+    /// Next state name
+    pub to: String,
+    /// Events emitted when the transition is taken
+    #[serde(default)]
+    pub emit: Vec<String>,
+    /// For how long the conditions must hold before taking the transition, in milliseconds
+    #[serde(default)]
+    pub duration: Option<f32>,
+}
+
+/// Warning raised when all its conditions are satisfied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Warning {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// How much the warning lowers the repetition score, defaults to 1.0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f32>,
+    #[serde(alias = "condition", deserialize_with = "one_or_many")]
+    pub conditions: Vec<Condition>,
+}
+
+/// Accept both a single condition and a list of conditions
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Condition>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Condition),
+        Many(Vec<Condition>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(condition) => vec![condition],
+        OneOrMany::Many(conditions) => conditions,
+    })
+}
+
+/// Single state of the FSM
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub name: String,
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+    /// Warnings checked only in this state
+    #[serde(default)]
+    pub warnings: Vec<Warning>,
+}
+
+/// Declarative description of an exercise as a finite state machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsmDescriptor {
+    pub states: Vec<State>,
+    pub initial_state: String,
+    /// Warnings checked in every state
+    #[serde(default)]
+    pub warnings: Vec<Warning>,
+    /// Joints that must be present in the frame, otherwise the frame is skipped
+    #[serde(default)]
+    pub required_joints: Vec<String>,
+    /// If not empty, only these control factors can be used by the exercise
+    #[serde(default)]
+    pub required_control_factors: Vec<String>,
+}
+
+/// Exercise represented using a declarative FSM
+#[derive(Debug)]
+pub struct FsmExercise {
+
+    /// Definition of the FSM
+    descriptor: FsmDescriptor,
+    /// Control factors available to the conditions
+    registry: Arc<ControlFactorRegistry>,
+    /// Confidence threshold, missing joints and smoothing of the keypoints before the evaluation
+    preprocessing: Preprocessing,
+    /// When the exercise is complete
+    completion: Completion,
+
+    /// Description of the exercise inside the database
+    pub description: String,
+    /// Name of the exercise inside the database
+    pub name: String,
+
+    /// Target number of repetitions to do
+    pub repetitions_target: u32,
+    /// Current number of repetitions done
+    pub repetitions: u32,
+    /// Processed frames split into repetitions, with the warnings used for the repetition score
+    pub repetition_log: RepetitionLog,
+
+    /// Current state name
+    pub current_state: String,
+    /// Since when the conditions of the transitions of the current state are satisfied,
+    /// indexed by transition, used by transitions with a duration
+    holding_since: HashMap<usize, f32>,
+
+    /// Processed frames, with skeleton and state output
+    pub frames: Vec<Frame>
+}
+
+/// Ranges are inclusive of both ends, a therapist writing 160-180 expects 180 to be valid
+fn in_range(range: &Range<f32>, value: f32) -> bool {
+    range.start <= value && value <= range.end
+}
+
+impl ConditionKind {
+    /// Check if the value satisfies the condition
+    fn check(&self, value: f32) -> bool {
+        match self {
+            ConditionKind::InRange { range } => in_range(range, value),
+            ConditionKind::NotInRange { range } => !in_range(range, value),
+            ConditionKind::GreaterThan { value: threshold } => value > *threshold,
+            ConditionKind::LessThan { value: threshold } => value < *threshold,
+        }
+    }
+
+    /// How far the value is from satisfying the condition, 0.0 if it is satisfied
+    fn distance(&self, value: f32) -> f32 {
+        match self {
+            ConditionKind::InRange { range } if value < range.start => range.start - value,
+            ConditionKind::InRange { range } if value > range.end => value - range.end,
+            ConditionKind::NotInRange { range } if in_range(range, value) =>
+                (value - range.start).min(range.end - value),
+            ConditionKind::GreaterThan { value: threshold } if value <= *threshold => threshold - value,
+            ConditionKind::LessThan { value: threshold } if value >= *threshold => value - threshold,
+            _ => 0.0
+        }
+    }
+}
+
+/// Convert the name of an emitted event into a StateEvent, unknown names are custom events
+fn parse_event(name: &str) -> StateEvent {
+    match name.to_ascii_lowercase().as_str() {
+        "repetitioncomplete" | "repetition_complete" | "repetition_completed" => StateEvent::Repetition,
+        _ => StateEvent::from_name(name),
+    }
+}
+
+impl FsmDescriptor {
+    /// Check that all the states, events and control factors referenced are defined
+    fn validate(&self, registry: &ControlFactorRegistry) -> Result<(), FsmError> {
+        let states: Vec<&str> = self.states.iter().map(|s| s.name.as_str()).collect();
+        if !states.contains(&self.initial_state.as_str()) {
+            return Err(FsmError::UnknownInitialState(self.initial_state.clone()));
+        }
+
+        let warnings = self.warnings.iter()
+            .chain(self.states.iter().flat_map(|s| s.warnings.iter()))
+            .flat_map(|w| w.conditions.iter());
+
+        for state in &self.states {
+            for transition in &state.transitions {
+                if !states.contains(&transition.to.as_str()) {
+                    return Err(FsmError::UnknownState { state: state.name.clone(), target: transition.to.clone() });
+                }
+                if let Some(event) = transition.emit.iter().find(|e| e.eq_ignore_ascii_case("phase")) {
+                    return Err(FsmError::UnnamedPhase { state: state.name.clone(), event: event.clone() });
+                }
+            }
+        }
+
+        let conditions = self.states.iter()
+            .flat_map(|s| s.transitions.iter())
+            .flat_map(|t| t.conditions.iter())
+            .chain(warnings);
+
+        let listed = self.required_control_factors.iter().map(|cf| cf.as_str());
+        for name in conditions.map(|c| c.control_factor.as_str()).chain(listed) {
+            if !registry.contains(name) {
+                return Err(FsmError::UnknownControlFactor(name.to_owned()));
+            }
+            if !self.required_control_factors.is_empty() && !self.required_control_factors.iter().any(|cf| cf == name) {
+                return Err(FsmError::UnlistedControlFactor(name.to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FsmExercise {
+
+    /// Initialize exercise from a JSON file
+    pub fn from_file(path: &Path, name: String, description: String, repetitions_target: u32) -> Result<Self, FsmError> {
+        let fsm = std::fs::read_to_string(path)?;
+        Self::from_string(fsm, name, description, repetitions_target)
+    }
+
+    /// Initialize exercise from a JSON string
+    pub fn from_string(fsm: String, name: String, description: String, repetitions_target: u32) -> Result<Self, FsmError> {
+        let descriptor: FsmDescriptor = serde_json::from_str(&fsm)?;
+        Self::from_descriptor(descriptor, name, description, repetitions_target)
+    }
+
+    /// Initialize exercise from an already parsed descriptor
+    pub fn from_descriptor(descriptor: FsmDescriptor, name: String, description: String, repetitions_target: u32) -> Result<Self, FsmError> {
+        let registry = Arc::new(ControlFactorRegistry::default());
+        descriptor.validate(&registry)?;
+
+        // Weights of the global and state warnings for the repetition score
+        let weights = descriptor.warnings.iter()
+            .chain(descriptor.states.iter().flat_map(|s| s.warnings.iter()))
+            .filter_map(|w| Some((w.name.clone(), w.weight?)))
+            .collect();
+
+        Ok(Self {
+            registry,
+            preprocessing: Preprocessing::default(),
+            completion: Completion::default(),
+            current_state: descriptor.initial_state.clone(),
+            descriptor,
+            name,
+            description,
+            repetitions_target,
+            repetitions: 0,
+            repetition_log: RepetitionLog::new(Scoring::new(weights)),
+            holding_since: HashMap::new(),
+            frames: vec![],
+        })
+    }
+
+    /// Use a different set of control factors, the FSM is validated again
+    pub fn with_registry(mut self, registry: Arc<ControlFactorRegistry>) -> Result<Self, FsmError> {
+        self.descriptor.validate(&registry)?;
+        self.registry = registry;
+        Ok(self)
+    }
+
+    /// Prepare the keypoints with the confidence threshold, missing joint policy and filter
+    pub fn with_preprocessing(mut self, preprocessing: Preprocessing) -> Self {
+        self.preprocessing = preprocessing;
+        self
+    }
+
+    /// Complete the exercise following the criteria instead of the repetitions,
+    /// fails if the criteria refers to a state that is not defined
+    pub fn with_completion(mut self, criteria: CompletionCriteria) -> Result<Self, MotionError> {
+        if let Some(state) = criteria.state().filter(|s| !self.descriptor.states.iter().any(|d| d.name == *s)) {
+            return Err(MotionError::UnknownCompletionState { exercise: self.name.clone(), state: state.to_owned() });
+        }
+        self.completion = Completion::new(criteria);
+        Ok(self)
+    }
+
+    /// Definition of the current state, fails if it was changed to a state that is not defined
+    fn state(&self) -> Result<&State, MotionError> {
+        self.descriptor.states.iter()
+            .find(|s| s.name == self.current_state)
+            .ok_or_else(|| MotionError::InvalidCurrentState { exercise: self.name.clone(), state: self.current_state.clone() })
+    }
+
+    /// Names of all the control factors used in the current state
+    fn required_control_factors(&self) -> Result<impl Iterator<Item = &str>, MotionError> {
+        let state = self.state()?;
+        Ok(state.transitions.iter()
+            .flat_map(|t| t.conditions.iter())
+            .chain(state.warnings.iter().flat_map(|w| w.conditions.iter()))
+            .chain(self.descriptor.warnings.iter().flat_map(|w| w.conditions.iter()))
+            .map(|c| c.control_factor.as_str()))
+    }
+
+    /// Update for how long the conditions of each transition are satisfied,
+    /// returns the index of the first transition that can be taken
+    fn update_transitions(&mut self, factors: &ControlFactors, time: f32) -> Result<Option<usize>, MotionError> {
+        let mut holding_since = std::mem::take(&mut self.holding_since);
+        let state = self.state()?;
+
+        let mut result = None;
+        for (i, transition) in state.transitions.iter().enumerate() {
+            let satisfied = transition.conditions.iter()
+                .all(|c| c.condition.check(factors[&c.control_factor]));
+
+            if !satisfied {
+                holding_since.remove(&i);
+                continue;
+            }
+
+            let since = *holding_since.entry(i).or_insert(time);
+            let required = transition.duration.unwrap_or(0.0) / 1000.0;
+            if result.is_none() && time - since >= required {
+                result = Some(i);
+            }
+        }
+
+        self.holding_since = holding_since;
+        Ok(result)
+    }
+
+    /// Evaluate the current state with the given control factors
+    fn evaluate(&mut self, factors: &ControlFactors, time: f32) -> Result<StateOutput, MotionError> {
+        let transition = self.update_transitions(factors, time)?;
+        let state = self.state()?;
+        let transition = transition.map(|i| &state.transitions[i]);
+
+        // Global and state warnings
+        let warnings = self.descriptor.warnings.iter()
+            .chain(state.warnings.iter())
+            .filter(|w| w.conditions.iter().all(|c| c.condition.check(factors[&c.control_factor])))
+            .map(|w| StateWarning {
+                name: w.name.clone(),
+                metadata: Some(w.conditions.iter()
+                    .map(|c| (c.control_factor.clone(), factors[&c.control_factor]))
+                    .collect()),
+            })
+            .collect();
+
+        // Distance of each control factor from the nearest transition
+        let mut delta = HashMap::<String, f32>::new();
+        for condition in state.transitions.iter().flat_map(|t| t.conditions.iter()) {
+            let distance = condition.condition.distance(factors[&condition.control_factor]);
+            delta.entry(condition.control_factor.clone())
+                .and_modify(|d| *d = d.min(distance))
+                .or_insert(distance);
+        }
+
+        Ok(StateOutput {
+            next_state: transition.map(|t| t.to.clone()),
+            metadata: Metadata {
+                events: transition
+                    .map(|t| t.emit.iter().map(|e| parse_event(e)).collect())
+                    .unwrap_or_default(),
+                warnings,
+                help: None,
+                widgets: vec![],
+                delta: Some(delta),
+            }
+        })
+    }
+
+    /// Handle a skeleton from the HPE with the frame index and capture time in seconds,
+    /// returns true when the exercise is complete and state output if the exercise did run.
+    pub fn process(&mut self, raw_skeleton: &Skeleton, index: u64, time: f32) -> Result<(bool, Option<StateOutput>), MotionError> {
+        // If any required joint is missing from the frame skeleton, skip processing
+        let Some(skeleton) = self.preprocessing.apply(raw_skeleton, &self.descriptor.required_joints, time) else {
+            return Ok((false, None));
+        };
+        let skeleton = &skeleton;
+
+        // If any required control factor cannot be computed, skip processing
+        let mut factors = ControlFactors::new();
+        for name in self.required_control_factors()? {
+            match self.registry.compute_one(name, skeleton) {
+                Some(value) => { factors.insert(name.to_owned(), value); },
+                None => return Ok((false, None)),
+            }
+        }
+
+        let output = self.evaluate(&factors, time)?;
+        let raw_skeleton = (raw_skeleton != skeleton).then(|| raw_skeleton.clone());
+        self.frames.push(Frame { index, time, skeleton: skeleton.clone(), raw_skeleton, output: output.clone() });
+        self.repetition_log.add_frame(self.frames.len() - 1, time, &self.current_state, &output);
+        if output.metadata.events.contains(&StateEvent::Repetition) {
+            self.repetitions += 1;
+        }
+
+        self.completion.update(time, &self.current_state, &output.metadata.events);
+
+        if let Some(next_state) = &output.next_state {
+            self.current_state = next_state.clone();
+            self.holding_since.clear();
+        }
+
+        let completed = self.completion.is_complete(self.repetitions, self.repetitions_target);
+        Ok((completed, Some(output)))
+    }
+}
+
+impl Exercise for FsmExercise {
+    fn process(&mut self, skeleton: &Skeleton, index: u64, time: f32) -> Result<(bool, Option<StateOutput>), MotionError> {
+        FsmExercise::process(self, skeleton, index, time)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn repetitions(&self) -> (u32, u32) {
+        (self.repetitions, self.repetitions_target)
+    }
+
+    fn repetition_log(&self) -> &RepetitionLog {
+        &self.repetition_log
+    }
+
+    fn current_state(&self) -> &str {
+        &self.current_state
+    }
+
+    fn required_joints(&self) -> &[String] {
+        &self.descriptor.required_joints
+    }
+
+    fn missing_joints(&self) -> &[String] {
+        self.preprocessing.missing()
+    }
+
+    fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    fn completion(&self) -> &Completion {
+        &self.completion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec2;
+
+    /// Raise both arms sideways and lower them, a repetition each time they are lowered
+    const EXERCISE: &str = include_str!("../../exercises/1/fsm.json");
+
+    /// Standing patient with both arms at the angle from the torso, in degrees
+    fn arms_at(angle: f32) -> Skeleton {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        [
+            ("left_shoulder", Vec2::new(60.0, 20.0)), ("right_shoulder", Vec2::new(40.0, 20.0)),
+            ("left_hip", Vec2::new(60.0, 60.0)), ("right_hip", Vec2::new(40.0, 60.0)),
+            ("left_elbow", Vec2::new(60.0 + 20.0 * sin, 20.0 + 20.0 * cos)),
+            ("right_elbow", Vec2::new(40.0 - 20.0 * sin, 20.0 + 20.0 * cos)),
+        ].into_iter().map(|(name, position)| (name.to_owned(), position.into())).collect()
+    }
+
+    fn exercise(json: &str, repetitions_target: u32) -> FsmExercise {
+        FsmExercise::from_string(json.to_owned(), "arms".to_owned(), String::new(), repetitions_target).unwrap()
+    }
+
+    /// Process the frames at 10 frames per second, returns the outputs
+    fn run(exercise: &mut FsmExercise, angles: &[f32]) -> Vec<(bool, Option<StateOutput>)> {
+        let start = exercise.frames.len();
+        angles.iter().enumerate()
+            .map(|(i, angle)| exercise.process(&arms_at(*angle), (start + i) as u64, (start + i) as f32 / 10.0).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn repetitions_are_counted() {
+        let mut exercise = exercise(EXERCISE, 2);
+        let outputs = run(&mut exercise, &[10.0, 60.0, 10.0, 60.0]);
+        assert_eq!(exercise.current_state, "up");
+        assert_eq!(exercise.repetitions, 1);
+        assert!(outputs.iter().all(|(completed, _)| !completed));
+        assert_eq!(outputs[2].1.as_ref().unwrap().metadata.events, [StateEvent::Repetition]);
+
+        let outputs = run(&mut exercise, &[60.0, 10.0]);
+        assert_eq!(exercise.repetitions, 2);
+        assert!(outputs[1].0);
+    }
+
+    #[test]
+    fn angles_outside_the_ranges_keep_the_state() {
+        let mut exercise = exercise(EXERCISE, 1);
+        run(&mut exercise, &[10.0, 35.0, 40.0, 100.0]);
+        assert_eq!(exercise.current_state, "down");
+        assert_eq!(exercise.frames.len(), 4);
+    }
+
+    #[test]
+    fn frames_without_the_control_factors_are_skipped() {
+        let mut exercise = exercise(EXERCISE, 1);
+        let mut skeleton = arms_at(10.0);
+        skeleton.remove("left_elbow");
+        assert!(exercise.process(&skeleton, 0, 0.0).unwrap().1.is_none());
+        assert!(exercise.frames.is_empty());
+        assert_eq!(exercise.current_state, "start");
+    }
+
+    #[test]
+    fn transitions_wait_for_their_duration() {
+        let json = EXERCISE.replacen(r#""to":"up","#, r#""to":"up", "duration": 250.0,"#, 1);
+        let mut exercise = exercise(&json, 1);
+        run(&mut exercise, &[10.0, 60.0, 60.0]);
+        assert_eq!(exercise.current_state, "down");
+        run(&mut exercise, &[60.0, 60.0]);
+        assert_eq!(exercise.current_state, "up");
+    }
+
+    #[test]
+    fn undefined_current_state_is_an_error() {
+        let mut exercise = exercise(EXERCISE, 1);
+        exercise.current_state = "sideways".to_owned();
+        let error = exercise.process(&arms_at(10.0), 0, 0.0).unwrap_err();
+        assert!(matches!(&error, MotionError::InvalidCurrentState { state, .. } if state == "sideways"), "{}", error);
+        assert!(exercise.frames.is_empty());
+    }
+
+    #[test]
+    fn undefined_states_are_rejected() {
+        let json = EXERCISE.replacen(r#""to":"up""#, r#""to":"upp""#, 1);
+        let error = FsmExercise::from_string(json, String::new(), String::new(), 1).err().unwrap();
+        assert!(matches!(error, FsmError::UnknownState { state, target } if state == "down" && target == "upp"));
+    }
+
+    #[test]
+    fn unknown_control_factors_are_rejected() {
+        let json = EXERCISE.replacen("arm_angle_l", "arm_angle_x", 1);
+        let error = FsmExercise::from_string(json, String::new(), String::new(), 1).err().unwrap();
+        assert!(matches!(error, FsmError::UnknownControlFactor(cf) if cf == "arm_angle_x"));
+    }
+
+    #[test]
+    fn repetition_events_are_recognized() {
+        assert_eq!(parse_event("RepetitionComplete"), StateEvent::Repetition);
+        assert_eq!(parse_event("repetition_completed"), StateEvent::Repetition);
+        assert_eq!(parse_event("start"), StateEvent::Start);
+    }
+}
@@ -0,0 +1,251 @@
+use mlua::prelude::*;
+use glam::Vec2;
+
+use crate::{LuaSkeleton, LuaVec2, Skeleton};
+
+// Directions in image coordinates, the y axis grows downward
+pub const UP:    Vec2 = Vec2::new( 0.0, -1.0);
+pub const DOWN:  Vec2 = Vec2::new( 0.0,  1.0);
+pub const LEFT:  Vec2 = Vec2::new(-1.0,  0.0);
+pub const RIGHT: Vec2 = Vec2::new( 1.0,  0.0);
+
+/// Distance between two points
+pub fn distance(a: Vec2, b: Vec2) -> f32 {
+    a.distance(b)
+}
+
+/// Middle point of the segment a->b
+pub fn midpoint(a: Vec2, b: Vec2) -> Vec2 {
+    (a + b) / 2.0
+}
+
+/// Inner angle in degrees between the segments z->a and z->b
+pub fn inner_angle(a: Vec2, z: Vec2, b: Vec2) -> f32 {
+    (a - z).angle_to(b - z).abs().to_degrees()
+}
+
+/// Angle in degrees between the segment a->b and the direction of the axis, between 0 and 180
+pub fn axis_angle(axis: Vec2, a: Vec2, b: Vec2) -> f32 {
+    (b - a).angle_to(axis).abs().to_degrees()
+}
+
+/// Angle in degrees between the segment a->b and the downward vertical axis,
+/// from 0 when b is below a to 180 when b is above a
+pub fn angle_to_vertical(a: Vec2, b: Vec2) -> f32 {
+    axis_angle(DOWN, a, b)
+}
+
+/// Angle in degrees between the segment a->b and the horizontal axis,
+/// from -90 when b is below a to 90 when b is above a
+pub fn angle_to_horizontal(a: Vec2, b: Vec2) -> f32 {
+    angle_to_vertical(a, b) - 90.0
+}
+
+/// Get the position of a joint from the skeleton
+pub fn joint(skeleton: &Skeleton, name: &str) -> Option<Vec2> {
+    skeleton.get(name).map(|j| j.position)
+}
+
+/// Middle point between the left and right joints with the same name, for example "hip"
+pub fn middle(skeleton: &Skeleton, name: &str) -> Option<Vec2> {
+    Some(midpoint(
+        joint(skeleton, &format!("left_{name}"))?,
+        joint(skeleton, &format!("right_{name}"))?
+    ))
+}
+
+/// Length of the torso from the neck, or the middle of the shoulders, to the middle of the hips.
+/// Used to compare distances independently of how far the patient is from the camera
+pub fn torso_length(skeleton: &Skeleton) -> Option<f32> {
+    let top = joint(skeleton, "neck").or_else(|| middle(skeleton, "shoulder"))?;
+    let length = distance(top, middle(skeleton, "hip")?);
+    (length > 0.0).then_some(length)
+}
+
+/// Distance between two points in torso lengths
+pub fn normalized_distance(skeleton: &Skeleton, a: Vec2, b: Vec2) -> Option<f32> {
+    Some(distance(a, b) / torso_length(skeleton)?)
+}
+
+/// Inner angle at the joint z on the left and right side of the body,
+/// the joints are named without the side, for example ["shoulder", "elbow", "wrist"]
+pub fn side_angles(skeleton: &Skeleton, [a, z, b]: [&str; 3]) -> Option<(f32, f32)> {
+    let angle = |side: &str| Some(inner_angle(
+        joint(skeleton, &format!("{side}_{a}"))?,
+        joint(skeleton, &format!("{side}_{z}"))?,
+        joint(skeleton, &format!("{side}_{b}"))?
+    ));
+    Some((angle("left")?, angle("right")?))
+}
+
+/// Difference in degrees between the left and right inner angle at the joint z, 0 is perfectly symmetric
+pub fn symmetry(skeleton: &Skeleton, joints: [&str; 3]) -> Option<f32> {
+    let (left, right) = side_angles(skeleton, joints)?;
+    Some((left - right).abs())
+}
+
+/// Convert the skeleton received from a script
+fn from_lua_skeleton(skeleton: LuaSkeleton) -> Skeleton {
+    skeleton.into_iter().map(|(k, v)| (k, v.0.into())).collect()
+}
+
+/// Register the geometry functions and the direction constants in the Lua context
+pub(crate) fn register_lua(ctx: &Lua) -> LuaResult<()> {
+    let globals = ctx.globals();
+
+    // Directions
+    for (name, direction) in [("UP", UP), ("DOWN", DOWN), ("LEFT", LEFT), ("RIGHT", RIGHT)] {
+        globals.set(name, LuaVec2(direction))?;
+    }
+
+    // Vector arithmetic
+    globals.set("vec2", ctx.create_function(|_, (x, y): (f32, f32)| Ok(LuaVec2(Vec2::new(x, y))))?)?;
+    globals.set("vec_add", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(LuaVec2(a.0 + b.0)))?)?;
+    globals.set("vec_sub", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(LuaVec2(a.0 - b.0)))?)?;
+    globals.set("vec_scale", ctx.create_function(|_, (a, k): (LuaVec2, f32)| Ok(LuaVec2(a.0 * k)))?)?;
+    globals.set("vec_dot", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(a.0.dot(b.0)))?)?;
+    globals.set("vec_length", ctx.create_function(|_, a: LuaVec2| Ok(a.0.length()))?)?;
+    globals.set("vec_normalize", ctx.create_function(|_, a: LuaVec2| Ok(LuaVec2(a.0.normalize_or_zero())))?)?;
+
+    // Points and segments
+    globals.set("distance", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(distance(a.0, b.0)))?)?;
+    globals.set("midpoint", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(LuaVec2(midpoint(a.0, b.0))))?)?;
+    globals.set("angle_to_vertical", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(angle_to_vertical(a.0, b.0)))?)?;
+    globals.set("angle_to_horizontal", ctx.create_function(|_, (a, b): (LuaVec2, LuaVec2)| Ok(angle_to_horizontal(a.0, b.0)))?)?;
+
+    // Skeleton, these return nil when the required joints are missing
+    globals.set("torso_length", ctx.create_function(|_, skeleton: LuaSkeleton| {
+        Ok(torso_length(&from_lua_skeleton(skeleton)))
+    })?)?;
+    globals.set("normalized_distance", ctx.create_function(|_, (skeleton, a, b): (LuaSkeleton, LuaVec2, LuaVec2)| {
+        Ok(normalized_distance(&from_lua_skeleton(skeleton), a.0, b.0))
+    })?)?;
+    globals.set("symmetry", ctx.create_function(|_, (skeleton, a, z, b): (LuaSkeleton, String, String, String)| {
+        Ok(symmetry(&from_lua_skeleton(skeleton), [&a, &z, &b]))
+    })?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LuaExercise, Sandbox};
+
+    const EPSILON: f32 = 1e-4;
+
+    fn skeleton(joints: &[(&str, (f32, f32))]) -> Skeleton {
+        joints.iter().map(|(k, (x, y))| (k.to_string(), Vec2::new(*x, *y).into())).collect()
+    }
+
+    /// Standing patient with the left arm raised horizontally and the right arm down
+    fn standing() -> Skeleton {
+        skeleton(&[
+            ("neck", (50.0, 20.0)),
+            ("left_shoulder", (60.0, 20.0)), ("right_shoulder", (40.0, 20.0)),
+            ("left_elbow", (80.0, 20.0)), ("right_elbow", (40.0, 40.0)),
+            ("left_wrist", (100.0, 20.0)), ("right_wrist", (40.0, 60.0)),
+            ("left_hip", (55.0, 60.0)), ("right_hip", (45.0, 60.0)),
+        ])
+    }
+
+    #[test]
+    fn directions_follow_image_coordinates() {
+        assert_eq!(UP + DOWN, Vec2::ZERO);
+        assert_eq!(LEFT + RIGHT, Vec2::ZERO);
+        assert_eq!(UP, Vec2::NEG_Y);
+        assert_eq!(RIGHT, Vec2::X);
+    }
+
+    #[test]
+    fn distance_and_midpoint() {
+        let (a, b) = (Vec2::new(0.0, 0.0), Vec2::new(3.0, 4.0));
+        assert!((distance(a, b) - 5.0).abs() < EPSILON);
+        assert_eq!(midpoint(a, b), Vec2::new(1.5, 2.0));
+    }
+
+    #[test]
+    fn inner_angle_is_symmetric_and_unsigned() {
+        let z = Vec2::ZERO;
+        assert!((inner_angle(RIGHT, z, UP) - 90.0).abs() < EPSILON);
+        assert!((inner_angle(UP, z, RIGHT) - 90.0).abs() < EPSILON);
+        assert!((inner_angle(LEFT, z, RIGHT) - 180.0).abs() < EPSILON);
+        assert!(inner_angle(RIGHT, z, RIGHT * 2.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn axis_angles_follow_the_direction_of_the_axis() {
+        let a = Vec2::ZERO;
+        assert!(axis_angle(RIGHT, a, RIGHT).abs() < EPSILON);
+        assert!((axis_angle(RIGHT, a, LEFT) - 180.0).abs() < EPSILON);
+        assert!((axis_angle(RIGHT, a, Vec2::new(-1.0, 1.0)) - 135.0).abs() < EPSILON);
+        assert!((axis_angle(RIGHT, a, Vec2::new(-1.0, -1.0)) - 135.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn raised_and_lowered_segments_have_different_angles() {
+        let a = Vec2::ZERO;
+        assert!(angle_to_vertical(a, DOWN).abs() < EPSILON);
+        assert!((angle_to_vertical(a, UP) - 180.0).abs() < EPSILON);
+        assert!((angle_to_vertical(a, LEFT) - 90.0).abs() < EPSILON);
+        assert!((angle_to_vertical(a, Vec2::new(1.0, -1.0)) - 135.0).abs() < EPSILON);
+
+        assert!(angle_to_horizontal(a, RIGHT).abs() < EPSILON);
+        assert!(angle_to_horizontal(a, LEFT).abs() < EPSILON);
+        assert!((angle_to_horizontal(a, UP) - 90.0).abs() < EPSILON);
+        assert!((angle_to_horizontal(a, DOWN) + 90.0).abs() < EPSILON);
+        assert!((angle_to_horizontal(a, Vec2::new(-1.0, -1.0)) - 45.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn middle_requires_both_sides() {
+        let s = standing();
+        assert_eq!(middle(&s, "hip"), Some(Vec2::new(50.0, 60.0)));
+        assert_eq!(middle(&s, "knee"), None);
+    }
+
+    #[test]
+    fn torso_length_uses_neck_or_shoulders() {
+        let mut s = standing();
+        assert!((torso_length(&s).unwrap() - 40.0).abs() < EPSILON);
+
+        s.remove("neck");
+        assert!((torso_length(&s).unwrap() - 40.0).abs() < EPSILON);
+
+        s.remove("left_hip");
+        assert_eq!(torso_length(&s), None);
+    }
+
+    #[test]
+    fn normalized_distance_in_torso_lengths() {
+        let s = standing();
+        let d = normalized_distance(&s, s["left_wrist"].position, s["right_wrist"].position).unwrap();
+        assert!((d - Vec2::new(60.0, -40.0).length() / 40.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn symmetry_compares_left_and_right() {
+        let s = standing();
+        let (left, right) = side_angles(&s, ["hip", "shoulder", "elbow"]).unwrap();
+        assert!(left > 90.0 && right < 30.0);
+        assert!((symmetry(&s, ["hip", "shoulder", "elbow"]).unwrap() - (left - right)).abs() < EPSILON);
+        assert_eq!(symmetry(&s, ["hip", "knee", "ankle"]), None);
+    }
+
+    #[test]
+    fn lua_bindings() {
+        let ctx = LuaExercise::create_lua_ctx(&Sandbox::default()).unwrap();
+        let result: (f32, f32, f32, f32, Option<f32>) = ctx.load(r#"
+            local s = { neck = vec2(0, 0), left_hip = vec2(-1, 10), right_hip = vec2(1, 10) }
+            local v = vec_add(vec_scale(RIGHT, 3), vec_sub(vec2(0, 4), vec2(0, 0)))
+            return vec_length(v), distance(UP, DOWN), angle_to_vertical(s.neck, s.left_hip),
+                   torso_length(s), symmetry(s, "shoulder", "elbow", "wrist")
+        "#).eval().unwrap();
+
+        assert!((result.0 - 5.0).abs() < EPSILON);
+        assert!((result.1 - 2.0).abs() < EPSILON);
+        assert!((result.2 - 1.0f32.atan2(10.0).to_degrees()).abs() < EPSILON);
+        assert!((result.3 - 10.0).abs() < EPSILON);
+        assert_eq!(result.4, None);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use glam::Vec2;
+
+use crate::Skeleton;
+
+/// Number of skeletons kept by default, about 3 seconds at 30 fps
+pub const DEFAULT_HISTORY_FRAMES: usize = 90;
+
+/// Bounded window of the last skeletons processed by an exercise, the newest is the current frame.
+/// Used by the scripts to evaluate how the joints move over time.
+#[derive(Debug, Clone)]
+pub struct History {
+    /// Maximum number of skeletons kept
+    capacity: usize,
+    /// Capture time in seconds and skeleton, from the oldest to the newest
+    samples: VecDeque<(f32, Skeleton)>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_FRAMES)
+    }
+}
+
+impl History {
+
+    /// Create an empty history keeping at most the given number of skeletons, at least 1
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self { capacity, samples: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Add the skeleton of a new frame, the oldest one is discarded when the window is full
+    pub fn push(&mut self, time: f32, skeleton: Skeleton) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((time, skeleton));
+    }
+
+    /// Number of skeletons in the window
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// True if no skeleton was added
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Capture time and skeleton of n frames ago, 0 is the current frame
+    pub fn ago(&self, frames: usize) -> Option<(f32, &Skeleton)> {
+        let index = self.samples.len().checked_sub(frames + 1)?;
+        self.samples.get(index).map(|(t, s)| (*t, s))
+    }
+
+    /// Capture time and position of a joint n frames ago, 0 is the current frame
+    pub fn joint_ago(&self, joint: &str, frames: usize) -> Option<(f32, Vec2)> {
+        let (time, skeleton) = self.ago(frames)?;
+        Some((time, skeleton.get(joint)?.position))
+    }
+
+    /// Velocity of a joint in units per second between the previous and the current frame
+    pub fn velocity(&self, joint: &str) -> Option<Vec2> {
+        self.velocity_at(joint, 0)
+    }
+
+    /// Acceleration of a joint in units per second squared over the last three frames
+    pub fn acceleration(&self, joint: &str) -> Option<Vec2> {
+        let (t0, _) = self.joint_ago(joint, 1)?;
+        let (t1, _) = self.joint_ago(joint, 0)?;
+        let dt = t1 - t0;
+        if dt <= 0.0 {
+            return None;
+        }
+        Some((self.velocity_at(joint, 0)? - self.velocity_at(joint, 1)?) / dt)
+    }
+
+    /// True if the joint moved less than the tolerance from its current position during the last seconds.
+    /// False if the window does not cover the seconds or the joint is missing from any frame in them
+    pub fn still(&self, joint: &str, seconds: f32, tolerance: f32) -> bool {
+        let Some((now, current)) = self.joint_ago(joint, 0) else {
+            return false;
+        };
+
+        for (time, skeleton) in self.samples.iter().rev() {
+            match skeleton.get(joint) {
+                Some(j) if j.position.distance(current) <= tolerance => {
+                    if now - time >= seconds {
+                        return true;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// Velocity of a joint ending n frames ago
+    fn velocity_at(&self, joint: &str, frames: usize) -> Option<Vec2> {
+        let (t0, p0) = self.joint_ago(joint, frames + 1)?;
+        let (t1, p1) = self.joint_ago(joint, frames)?;
+        let dt = t1 - t0;
+        (dt > 0.0).then(|| (p1 - p0) / dt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    /// History of the left wrist at the given (time, x, y) samples
+    fn wrist(samples: &[(f32, f32, f32)]) -> History {
+        let mut history = History::default();
+        for (time, x, y) in samples {
+            history.push(*time, Skeleton::from([("left_wrist".to_owned(), Vec2::new(*x, *y).into())]));
+        }
+        history
+    }
+
+    fn assert_near(value: Vec2, expected: Vec2) {
+        assert!(value.distance(expected) < EPSILON, "{value} != {expected}");
+    }
+
+    #[test]
+    fn window_keeps_the_newest_frames() {
+        let mut history = History::new(3);
+        for time in 0..5 {
+            history.push(time as f32, Skeleton::new());
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.ago(0).map(|(t, _)| t), Some(4.0));
+        assert_eq!(history.ago(2).map(|(t, _)| t), Some(2.0));
+        assert!(history.ago(3).is_none());
+
+        // The window keeps at least the current frame
+        let mut history = History::new(0);
+        history.push(0.0, Skeleton::new());
+        history.push(1.0, Skeleton::new());
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn joint_ago_counts_from_the_current_frame() {
+        let history = wrist(&[(0.0, 0.0, 0.0), (0.5, 2.0, 0.0), (1.0, 2.0, 3.0)]);
+        assert_eq!(history.joint_ago("left_wrist", 0), Some((1.0, Vec2::new(2.0, 3.0))));
+        assert_eq!(history.joint_ago("left_wrist", 2), Some((0.0, Vec2::ZERO)));
+        assert_eq!(history.joint_ago("left_wrist", 3), None);
+        assert_eq!(history.joint_ago("right_wrist", 0), None);
+    }
+
+    #[test]
+    fn velocity_and_acceleration_use_the_capture_times() {
+        let history = wrist(&[(0.0, 0.0, 0.0), (0.5, 2.0, 0.0), (1.0, 2.0, 3.0)]);
+        let velocity = history.velocity("left_wrist").unwrap();
+        assert_near(velocity, Vec2::new(0.0, 6.0));
+        assert!((velocity.length() - 6.0).abs() < EPSILON);
+
+        // From (4, 0) to (0, 6) units per second in half a second
+        assert_near(history.acceleration("left_wrist").unwrap(), Vec2::new(-8.0, 12.0));
+    }
+
+    #[test]
+    fn frames_with_the_same_time_have_no_velocity() {
+        let history = wrist(&[(0.0, 0.0, 0.0), (0.5, 2.0, 0.0), (0.5, 4.0, 0.0)]);
+        assert_eq!(history.velocity("left_wrist"), None);
+        assert_eq!(history.acceleration("left_wrist"), None);
+
+        // Not enough frames
+        let history = wrist(&[(0.0, 0.0, 0.0), (0.5, 2.0, 0.0)]);
+        assert!(history.velocity("left_wrist").is_some());
+        assert_eq!(history.acceleration("left_wrist"), None);
+        assert_eq!(wrist(&[(0.0, 0.0, 0.0)]).velocity("left_wrist"), None);
+    }
+
+    #[test]
+    fn still_requires_the_whole_period() {
+        let history = wrist(&[(0.0, 10.0, 0.0), (0.5, 0.5, 0.0), (1.0, 0.0, 0.0), (1.5, 0.2, 0.0)]);
+        assert!(history.still("left_wrist", 1.0, 1.0));
+        assert!(!history.still("left_wrist", 1.5, 1.0));
+        assert!(!history.still("left_wrist", 0.5, 0.1));
+        assert!(!history.still("right_wrist", 0.0, 1.0));
+    }
+}
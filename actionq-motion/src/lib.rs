@@ -1,9 +1,131 @@
 #![allow(dead_code, unused_imports)]
 
-use std::{collections::HashMap, ops::Deref, path::Path};
+use std::{collections::HashMap, ops::Deref, path::Path, sync::Arc};
 use mlua::prelude::*;
 use glam::Vec2;
 
+pub mod completion;
+mod error;
+pub mod factors;
+pub mod filter;
+pub mod fsm;
+pub mod geometry;
+pub mod history;
+pub mod missing;
+pub mod parameters;
+mod preprocess;
+mod sandbox;
+pub mod repetition;
+pub mod rom;
+pub mod score;
+#[cfg(test)]
+mod testing;
+pub mod validate;
+mod yaml;
+pub use completion::{Completion, CompletionCriteria};
+pub use error::{InvalidValue, MotionError};
+pub use factors::{ControlFactorRegistry, ControlFactors};
+pub use filter::{FilterConfig, SkeletonFilter};
+pub use fsm::{FsmExercise, FsmError};
+pub use history::History;
+pub use missing::{JointRecovery, MissingJointPolicy};
+pub use parameters::{ParameterError, ParameterValue, Parameters};
+pub use preprocess::Preprocessing;
+pub use sandbox::Sandbox;
+pub use repetition::{Bounds, Repetition, RepetitionLog};
+pub use rom::{AngleStats, RangeOfMotion, RangeOfMotionReport};
+pub use score::Scoring;
+pub use validate::{validate_lua, ValidationIssue};
+
+/// Error returned while creating an exercise with any of the backends
+pub type ExerciseError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Format of an exercise definition, each one is evaluated by a different backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExerciseFormat {
+    /// Lua script
+    #[default]
+    Lua,
+    /// Declarative FSM in JSON
+    Json,
+    /// Declarative FSM in YAML
+    Yaml,
+}
+
+impl ExerciseFormat {
+    /// File extensions of each format, '.fsm' is used by the older JSON definitions
+    pub const EXTENSIONS: [(&'static str, Self); 5] = [
+        ("lua", Self::Lua),
+        ("json", Self::Json),
+        ("fsm", Self::Json),
+        ("yaml", Self::Yaml),
+        ("yml", Self::Yaml),
+    ];
+
+    /// Format of a definition file from its extension, ignoring the case
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::EXTENSIONS.iter()
+            .find(|(e, _)| e.eq_ignore_ascii_case(extension))
+            .map(|(_, format)| *format)
+    }
+}
+
+/// Common interface of the exercise backends, used by the session to run any exercise
+pub trait Exercise: std::fmt::Debug + Send + Sync {
+
+    /// Handle a skeleton from the HPE with the frame index and capture time in seconds,
+    /// returns true when the exercise is complete and state output if the exercise did run.
+    fn process(&mut self, skeleton: &Skeleton, index: u64, time: f32) -> Result<(bool, Option<StateOutput>), MotionError>;
+
+    /// Name of the exercise inside the database
+    fn name(&self) -> &str;
+
+    /// Description of the exercise inside the database
+    fn description(&self) -> &str;
+
+    /// Number of repetitions done and target number of repetitions
+    fn repetitions(&self) -> (u32, u32);
+
+    /// Records of the repetitions, with their duration and score
+    fn repetition_log(&self) -> &RepetitionLog;
+
+    /// Current state name
+    fn current_state(&self) -> &str;
+
+    /// Joints that must be present in a frame to process it
+    fn required_joints(&self) -> &[String];
+
+    /// Required joints missing from the last frame, that was not evaluated
+    fn missing_joints(&self) -> &[String];
+
+    /// Processed frames, with skeleton and state output
+    fn frames(&self) -> &[Frame];
+
+    /// Progress towards the completion criteria
+    fn completion(&self) -> &Completion;
+
+    /// Invoked once when the exercise becomes the current one, before its first frame
+    fn setup(&mut self) -> Result<(), MotionError> {
+        Ok(())
+    }
+
+    /// Invoked when the session is paused
+    fn pause(&mut self) -> Result<(), MotionError> {
+        Ok(())
+    }
+
+    /// Invoked when the session is resumed after a pause
+    fn resume(&mut self) -> Result<(), MotionError> {
+        Ok(())
+    }
+
+    /// Invoked once when the exercise ends, either completed or interrupted
+    fn teardown(&mut self) -> Result<(), MotionError> {
+        Ok(())
+    }
+}
+
 /// Exercise represented using a Lua script
 #[derive(Debug)]
 pub struct LuaExercise {
@@ -24,18 +146,56 @@ pub struct LuaExercise {
     /// What are the required joints to observe for this script
     required_joints: Vec<String>,
 
+    /// Control factors computed at each frame and passed to the state functions
+    registry: Arc<ControlFactorRegistry>,
+    /// Confidence threshold, missing joints and smoothing of the keypoints before the evaluation
+    preprocessing: Preprocessing,
+    /// When the exercise is complete
+    completion: Completion,
+    /// Parameters declared by the script in PARAMETERS with their defaults
+    declared_parameters: Parameters,
+    /// True once the 'setup' function was invoked
+    set_up: bool,
+    /// True once the 'teardown' function was invoked
+    torn_down: bool,
+
     /// All invokable functions from the engine, 
     /// includes "setup", "load" and all functions defined in the STATES global variable 
     functions: HashMap<String, LuaFunction>,
     /// Current state name
     pub current_state: String,
 
-    /// Accumulated warnings during the current repetition
-    /// This get reseted at each repetition and is used for the repetition score
-    accumulated_warnings: HashMap<String, u32>,
+    /// Processed frames split into repetitions, with the warnings used for the repetition score
+    pub repetition_log: RepetitionLog,
 
     /// Processed frames, with skeleton and state output
-    pub frames: Vec<(Skeleton, StateOutput)>
+    pub frames: Vec<Frame>
+}
+
+/// A processed frame
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Sequence number of the frame assigned by the pose source
+    pub index: u64,
+    /// Capture time of the frame, in seconds
+    pub time: f32,
+    /// Skeleton evaluated by the exercise, after the filter
+    pub skeleton: Skeleton,
+    /// Skeleton received from the pose estimator, None when it is the same as the evaluated one
+    pub raw_skeleton: Option<Skeleton>,
+    /// Output of the state evaluated on this frame
+    pub output: StateOutput,
+}
+
+/// Timing of the exercise, shared with the Lua helpers as app data
+#[derive(Debug, Default)]
+struct Timing {
+    /// Time of the current frame, in seconds
+    time: f32,
+    /// When the current state was entered, in seconds
+    state_since: f32,
+    /// Since when each named condition of the 'hold' helper is satisfied
+    holds: HashMap<String, f32>,
 }
 
 /// Custom widget to draw on screen over the video stream.
@@ -99,13 +259,59 @@ pub struct StateOutput {
     pub metadata: Metadata
 }
 
-/// Supported events during the exercise
-#[derive(Debug, Clone, PartialEq)]
+/// Events emitted during the exercise, the built-in ones are understood by the engine
+/// while the custom ones are only forwarded to the UI and stored with the session
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum StateEvent {
     /// The patient is in the correct initial position
     Start,
     /// The patient has completed a repetition
-    Repetition
+    Repetition,
+    /// The patient has completed part of a repetition, for example the raising half
+    PartialRepetition {
+        /// Fraction of the repetition done, between 0 and 1
+        progress: f32,
+    },
+    /// The exercise moved to another phase, for example from raising to lowering the arm
+    Phase { name: String },
+    /// The exercise is complete, used with the 'event' completion criteria
+    Complete,
+    /// Event defined by the script, for example 'side_switch' or 'hold_reached'
+    Custom {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<HashMap<String, f32>>,
+    },
+}
+
+impl StateEvent {
+    /// Parse the name of an event without payload, 'phase:<name>' is a phase change
+    /// and the names that are not built-in are custom events
+    pub fn from_name(name: &str) -> Self {
+        if let Some((_, phase)) = name.split_once(':').filter(|(kind, _)| kind.eq_ignore_ascii_case("phase")) {
+            return Self::Phase { name: phase.to_owned() };
+        }
+        match name.to_ascii_lowercase().as_str() {
+            "start" => Self::Start,
+            "repetition" => Self::Repetition,
+            "complete" => Self::Complete,
+            "partial_repetition" | "half_repetition" => Self::PartialRepetition { progress: 0.5 },
+            _ => Self::Custom { name: name.to_owned(), payload: None },
+        }
+    }
+
+    /// Name of the event, as written by the scripts
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Start => "start",
+            Self::Repetition => "repetition",
+            Self::PartialRepetition { .. } => "partial_repetition",
+            Self::Phase { .. } => "phase",
+            Self::Complete => "complete",
+            Self::Custom { name, .. } => name,
+        }
+    }
 }
 
 /// An error is represented by a name and some optional metadata
@@ -115,132 +321,217 @@ pub struct StateWarning {
     pub metadata: Option<HashMap<String, f32>>,
 }
 
+/// Short description of a Lua value for the error messages
+fn describe(value: &LuaValue) -> String {
+    match value {
+        LuaValue::Nil => "nil".to_owned(),
+        LuaValue::Boolean(b) => b.to_string(),
+        LuaValue::Integer(i) => i.to_string(),
+        LuaValue::Number(n) => n.to_string(),
+        LuaValue::String(s) => format!("\"{}\"", s.to_string_lossy()),
+        other => other.type_name().to_owned(),
+    }
+}
+
+/// Error for a value that cannot be converted, the field is relative to the converted value
+fn invalid(field: &str, value: &LuaValue, expected: &str) -> LuaError {
+    LuaError::external(InvalidValue {
+        field: field.to_owned(),
+        value: describe(value),
+        expected: expected.to_owned(),
+    })
+}
+
+/// Convert a value nested in the given field, the field is prepended to the path of the nested errors
+fn convert<T: FromLua>(lua: &Lua, field: &str, value: LuaValue, expected: &str) -> LuaResult<T> {
+    T::from_lua(value.clone(), lua).map_err(|e| match e.downcast_ref::<InvalidValue>() {
+        Some(inner) => {
+            let separator = if inner.field.is_empty() || inner.field.starts_with('[') { "" } else { "." };
+            LuaError::external(InvalidValue {
+                field: format!("{}{}{}", field, separator, inner.field),
+                ..inner.clone()
+            })
+        },
+        None => invalid(field, &value, expected),
+    })
+}
+
+/// Get a field of a table
+fn get_field<T: FromLua>(lua: &Lua, table: &LuaTable, field: &str, expected: &str) -> LuaResult<T> {
+    convert(lua, field, table.get(field)?, expected)
+}
+
+/// Get a list from a field of a table, a missing field is an empty list
+fn get_list<T: FromLua>(lua: &Lua, table: &LuaTable, field: &str, expected: &str) -> LuaResult<Vec<T>> {
+    match table.get::<LuaValue>(field)? {
+        LuaValue::Nil => Ok(vec![]),
+        LuaValue::Table(list) => list.sequence_values::<LuaValue>()
+            .enumerate()
+            .map(|(i, item)| convert(lua, &format!("{}[{}]", field, i + 1), item?, expected))
+            .collect(),
+        other => Err(invalid(field, &other, "a list")),
+    }
+}
+
 /// Create a Widget from a Lua table
 impl FromLua for Widget {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
-        if let LuaValue::Table(t) = value {
-            let widget_type: String = t.get("widget")?;
-            return Ok(match widget_type.as_str() {
-                "circle" => {
-
-                    // From LuaVec2 to Vec2 with optionally None
-                    let position: LuaVec2 = t.get("position")?;
-                    
-                    // BUG: this is Nil
-                    //let text_offset: LuaVec2 = t.get("text_offset")
-                    //    .unwrap_or(LuaVec2(Vec2::new(0.0, 0.0)));
-
-                    let text_offset = Vec2::new(0.0, 0.0);
-
-                    Widget::Circle {
-                        position: position.0,
-                        text_offset: text_offset,
-                        text: t.get("text").ok(),
-                    }
-                },
-                "segment" => {
-                    
-                    let from: LuaVec2 = t.get("from")?;
-                    let to: LuaVec2 = t.get("to")?;
-                    
-                    Widget::Segment { 
-                        from: from.0, 
-                        to: to.0 
-                    }
-                },
-                "hline" => Widget::HLine { y: t.get("y")? },
-                "vline" => Widget::VLine { x: t.get("x")? },
-                _ => unimplemented!()
-            })
-        }
-        unimplemented!()
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(t) = value else {
+            return Err(invalid("", &value, "a widget table"));
+        };
+
+        let widget_type: LuaValue = t.get("widget")?;
+        Ok(match widget_type.as_str().as_deref() {
+            Some("circle") => {
+
+                // From LuaVec2 to Vec2 with optionally None
+                let position: LuaVec2 = get_field(lua, &t, "position", "a vector")?;
+                
+                // BUG: this is Nil
+                //let text_offset: LuaVec2 = t.get("text_offset")
+                //    .unwrap_or(LuaVec2(Vec2::new(0.0, 0.0)));
+
+                let text_offset = Vec2::new(0.0, 0.0);
+
+                Widget::Circle {
+                    position: position.0,
+                    text_offset,
+                    text: get_field(lua, &t, "text", "a string")?,
+                }
+            },
+            Some("segment") => {
+                
+                let from: LuaVec2 = get_field(lua, &t, "from", "a vector")?;
+                let to: LuaVec2 = get_field(lua, &t, "to", "a vector")?;
+                
+                Widget::Segment { 
+                    from: from.0, 
+                    to: to.0 
+                }
+            },
+            Some("hline") => Widget::HLine { y: get_field(lua, &t, "y", "a number")? },
+            Some("vline") => Widget::VLine { x: get_field(lua, &t, "x", "a number")? },
+            _ => return Err(invalid("widget", &widget_type, "'circle', 'segment', 'hline' or 'vline'"))
+        })
     }
 }
 
-/// Create a StateEvent from a Lua string
+/// Create a StateEvent from a Lua string with the name of the event, or from a table
+/// with the name and the data of the event
 impl FromLua for StateEvent {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
-        if let LuaValue::String(s) = value {
-            return Ok(
-                match s.to_str()?.to_ascii_lowercase().as_str() {
-                    "start" => StateEvent::Start,
-                    "repetition" => StateEvent::Repetition,
-                    _ => unimplemented!()
-                }
-            );
-        }
-        unimplemented!()
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let t = match value {
+            LuaValue::String(name) => return Ok(StateEvent::from_name(&name.to_str()?)),
+            LuaValue::Table(t) => t,
+            other => return Err(invalid("", &other, "an event name or an event table")),
+        };
+
+        let name: String = get_field(lua, &t, "name", "a string")?;
+        Ok(match StateEvent::from_name(&name) {
+            StateEvent::PartialRepetition { .. } => {
+                let progress: Option<f32> = get_field(lua, &t, "progress", "a number")?;
+                StateEvent::PartialRepetition { progress: progress.unwrap_or(0.5) }
+            },
+            StateEvent::Custom { .. } if name.eq_ignore_ascii_case("phase") => StateEvent::Phase {
+                name: get_field(lua, &t, "phase", "a string")?,
+            },
+            StateEvent::Custom { name, .. } => StateEvent::Custom {
+                name,
+                payload: get_field(lua, &t, "payload", "a table of numbers")?,
+            },
+            event => event,
+        })
     }
 }
 
 /// Create a StateWarning from a Lua table
 impl FromLua for StateWarning {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
-        if let LuaValue::Table(t) = value {
-            return Ok(
-                Self {
-                    metadata: t.get("metadata").ok(),
-                    name: t.get("name")?,
-                }
-            )
-        }
-        unimplemented!()
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(t) = value else {
+            return Err(invalid("", &value, "a warning table"));
+        };
+
+        Ok(Self {
+            metadata: get_field(lua, &t, "metadata", "a table of numbers")?,
+            name: get_field(lua, &t, "name", "a string")?,
+        })
     }
 }
 
 /// Create a state Metadata from a Lua table
 impl FromLua for Metadata {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
         match value {
             LuaValue::Nil => Ok(Self { warnings: vec![], events: vec![], widgets: vec![], help: None, delta: None }),
             LuaValue::Table(t) => Ok(
                 Self { 
-                    widgets: t.get("widgets").unwrap_or(vec![]),
-                    warnings: t.get("warnings").unwrap_or(vec![]),
-                    events: t.get("events").unwrap_or(vec![]),
-                    delta: t.get("delta").ok(),
-                    help: t.get("help").ok(),
+                    widgets: get_list(lua, &t, "widgets", "a widget table")?,
+                    warnings: get_list(lua, &t, "warnings", "a warning table")?,
+                    events: get_list(lua, &t, "events", "an event name or an event table")?,
+                    delta: get_field(lua, &t, "delta", "a table of numbers")?,
+                    help: get_field(lua, &t, "help", "a string")?,
                 }
             ),
-            _ => unimplemented!()
+            _ => Err(invalid("", &value, "a metadata table or nil"))
         }
     }
 }
 
 /// Create StateOutput from a Lua table
 impl FromLua for StateOutput {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
-        if let LuaValue::Table(t) = value {
-            return Ok(
-                Self {
-                    next_state: t.get("next_state").ok(),
-                    metadata: t.get("metadata")?
-                }
-            )
-        }
-        unimplemented!()
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(t) = value else {
+            return Err(invalid("", &value, "the result of 'stay' or 'step'"));
+        };
+
+        Ok(Self {
+            next_state: get_field(lua, &t, "next_state", "a state name")?,
+            metadata: get_field(lua, &t, "metadata", "a metadata table or nil")?
+        })
     }
 }
 
 /// Skeleton with the minimum amount of data required to work
 pub type LuaSkeleton = HashMap<String, LuaVec2>;
-pub type Skeleton = HashMap<String, Vec2>;
+pub type Skeleton = HashMap<String, Joint>;
+
+/// Joint of the skeleton observed by the pose estimator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Joint {
+    /// Position in image coordinates
+    pub position: Vec2,
+    /// Confidence of the pose estimator, between 0 and 1
+    pub confidence: f32,
+}
+
+impl Joint {
+    pub fn new(position: Vec2, confidence: f32) -> Self {
+        Self { position, confidence }
+    }
+}
+
+/// Joint with full confidence, for the sources that do not estimate it
+impl From<Vec2> for Joint {
+    fn from(position: Vec2) -> Self {
+        Self { position, confidence: 1.0 }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct LuaVec2(Vec2);
 
 /// Convert a Vec2 into a LuaVec2. 
 /// Necessary to implement traits on the Vec2 struct from glam.
-impl Into<LuaVec2> for Vec2 {
-    fn into(self) -> LuaVec2 {
-        LuaVec2(self)
+impl From<Vec2> for LuaVec2 {
+    fn from(value: Vec2) -> Self {
+        LuaVec2(value)
     }
 }
 
 /// Convert a LuaVec2 into a Vec2
-impl Into<Vec2> for LuaVec2 {
-    fn into(self) -> Vec2 {
-        self.0
+impl From<LuaVec2> for Vec2 {
+    fn from(value: LuaVec2) -> Self {
+        value.0
     }
 }
 
@@ -256,21 +547,20 @@ impl IntoLua for LuaVec2 {
 
 /// Convert Lua table into a LuaVec2
 impl FromLua for LuaVec2 {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
-        //println!("{:?}", value);
-        if let LuaValue::Table(t) = value {
-            return Ok(LuaVec2(Vec2::new(
-                t.get("x")?, t.get("y")?)))
-        }
-        unimplemented!()
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(t) = value else {
+            return Err(invalid("", &value, "a vector"));
+        };
+        Ok(LuaVec2(Vec2::new(
+            get_field(lua, &t, "x", "a number")?, get_field(lua, &t, "y", "a number")?)))
     }
 }
 
 impl LuaExercise {
 
     /// Insert all global data into lua context
-    fn create_lua_ctx() -> LuaResult<Lua> {
-        let ctx = Lua::new();
+    fn create_lua_ctx(sandbox: &Sandbox) -> LuaResult<Lua> {
+        let ctx = sandbox::create_lua(sandbox)?;
 
         // Stay on same state, optionally supports state metadata
         ctx.globals().set("stay", 
@@ -292,7 +582,8 @@ impl LuaExercise {
             })?
         )?;
 
-        // TODO: Skeleton functions
+        // Skeleton geometry and directions
+        geometry::register_lua(&ctx)?;
 
         // Inner angle
         ctx.globals().set("inner_angle",
@@ -332,6 +623,30 @@ impl LuaExercise {
             })?
         )?;
 
+        // Seconds since the current state is active
+        ctx.globals().set("state_time",
+            ctx.create_function(|lua, ()| {
+                let timing = lua.app_data_ref::<Timing>().expect("missing timing");
+                Ok(timing.time - timing.state_since)
+            })?
+        )?;
+
+        // True if the named condition is satisfied for at least the given seconds,
+        // also returns for how many seconds it has been satisfied. Resets at each state change.
+        ctx.globals().set("hold",
+            ctx.create_function(|lua, (name, condition, seconds): (String, bool, f32)| {
+                let mut timing = lua.app_data_mut::<Timing>().expect("missing timing");
+                if !condition {
+                    timing.holds.remove(&name);
+                    return Ok((false, 0.0));
+                }
+
+                let time = timing.time;
+                let elapsed = time - *timing.holds.entry(name).or_insert(time);
+                Ok((elapsed >= seconds, elapsed))
+            })?
+        )?;
+
         // Near values by a margin
         ctx.globals().set("near",
             ctx.create_function(|_, (target, margin, value): (f32, f32, f32)| {
@@ -340,20 +655,136 @@ impl LuaExercise {
             })?
         )?;
 
+        // Position of a joint n frames ago, 0 is the current frame
+        ctx.globals().set("joint_ago",
+            ctx.create_function(|lua, (joint, frames): (String, usize)| {
+                let history = lua.app_data_ref::<History>().expect("missing history");
+                Ok(history.joint_ago(&joint, frames).map(|(_, p)| LuaVec2(p)))
+            })?
+        )?;
+
+        // Velocity of a joint between the previous and the current frame, in units per second
+        ctx.globals().set("velocity",
+            ctx.create_function(|lua, joint: String| {
+                let history = lua.app_data_ref::<History>().expect("missing history");
+                Ok(history.velocity(&joint).map(LuaVec2))
+            })?
+        )?;
+
+        // Speed of a joint between the previous and the current frame, in units per second
+        ctx.globals().set("speed",
+            ctx.create_function(|lua, joint: String| {
+                let history = lua.app_data_ref::<History>().expect("missing history");
+                Ok(history.velocity(&joint).map(Vec2::length))
+            })?
+        )?;
+
+        // Acceleration of a joint over the last three frames, in units per second squared
+        ctx.globals().set("acceleration",
+            ctx.create_function(|lua, joint: String| {
+                let history = lua.app_data_ref::<History>().expect("missing history");
+                Ok(history.acceleration(&joint).map(LuaVec2))
+            })?
+        )?;
+
+        // True if the joint moved less than the tolerance during the last seconds
+        ctx.globals().set("still",
+            ctx.create_function(|lua, (joint, seconds, tolerance): (String, f32, f32)| {
+                let history = lua.app_data_ref::<History>().expect("missing history");
+                Ok(history.still(&joint, seconds, tolerance))
+            })?
+        )?;
+
+        // Confidence of a joint in the current frame, nil if the joint is missing
+        ctx.globals().set("confidence",
+            ctx.create_function(|lua, joint: String| {
+                let history = lua.app_data_ref::<History>().expect("missing history");
+                Ok(history.ago(0).and_then(|(_, s)| s.get(&joint)).map(|j| j.confidence))
+            })?
+        )?;
+
+        ctx.set_app_data(Timing::default());
+        ctx.set_app_data(History::default());
         Ok(ctx)
     }
 
-    /// Initialize exercise from Lua script as a file
-    pub fn from_file(path: &Path, name: String, description: String, repetitions_target: u32) -> LuaResult<Self> {
+    /// Initialize exercise from Lua script as a file, the script runs in the default sandbox
+    pub fn from_file(path: &Path, name: String, description: String, repetitions_target: u32) -> Result<Self, MotionError> {
         let fsm = std::fs::read_to_string(path)?;
         Self::from_string(fsm, name, description, repetitions_target)
     }
 
-    /// Initialize esercise from Lua script as a string 
-    pub fn from_string(script: String, name: String, description: String, repetitions_target: u32) -> LuaResult<Self> {
-        
-        let ctx = Self::create_lua_ctx()?;
-        ctx.load(script).exec()?;
+    /// Initialize esercise from Lua script as a string, the script runs in the default sandbox
+    pub fn from_string(script: String, name: String, description: String, repetitions_target: u32) -> Result<Self, MotionError> {
+        Self::from_string_with_sandbox(script, name, description, repetitions_target, &Sandbox::default())
+    }
+
+    /// Initialize esercise from Lua script as a string with custom restrictions
+    pub fn from_string_with_sandbox(script: String, name: String, description: String, repetitions_target: u32, sandbox: &Sandbox) -> Result<Self, MotionError> {
+        let (ctx, functions, required_joints) = Self::load_script(script, &name, sandbox)
+            .map_err(|source| MotionError::Load { exercise: name.clone(), source: Box::new(source) })?;
+
+        // Optional weights of the warnings for the repetition score
+        let weights = ctx.globals().get::<Option<HashMap<String, f32>>>("WARNING_WEIGHTS")
+            .map_err(|source| MotionError::Load { exercise: name.clone(), source: Box::new(source) })?
+            .unwrap_or_default();
+
+        // Optional number of previous skeletons available to the history helpers
+        let history_frames = ctx.globals().get::<Option<usize>>("HISTORY_FRAMES")
+            .map_err(|source| MotionError::Load { exercise: name.clone(), source: Box::new(source) })?;
+        if let Some(frames) = history_frames {
+            ctx.set_app_data(History::new(frames));
+        }
+
+        // Optional parameters with their defaults, the scripts read the values of the session from PARAMS
+        let declared_parameters = ctx.globals().get::<Option<Parameters>>("PARAMETERS")
+            .and_then(|parameters| {
+                let parameters = parameters.unwrap_or_default();
+                ctx.globals().set("PARAMS", parameters.clone())?;
+                Ok(parameters)
+            })
+            .map_err(|source| MotionError::Load { exercise: name.clone(), source: Box::new(source) })?;
+
+        Ok(Self {
+            ctx, 
+            name,
+            description,
+            repetitions_target, 
+            required_joints,
+            registry: Arc::new(ControlFactorRegistry::default()),
+            preprocessing: Preprocessing::default(),
+            completion: Completion::default(),
+            declared_parameters,
+            set_up: false,
+            torn_down: false,
+            current_state: "entry".to_string(),
+            repetition_log: RepetitionLog::new(Scoring::new(weights)),
+            frames: vec![],
+            repetitions: 0, 
+            functions,
+        })
+    }
+
+    /// Run the script and obtain the invokable functions and the required joints,
+    /// the errors of the script refer to it by the name of the exercise
+    fn load_script(script: String, name: &str, sandbox: &Sandbox) -> LuaResult<(Lua, HashMap<String, LuaFunction>, Vec<String>)> {
+        let ctx = Self::create_lua_ctx(sandbox)?;
+
+        // The top level of the script can read the parameters, with the defaults declared in PARAMETERS.
+        // The values of the session replace them once the script is loaded
+        let defaults = ctx.create_table()?;
+        let metatable = ctx.create_table()?;
+        metatable.set("__index", ctx.create_function(|lua, (_, name): (LuaTable, LuaValue)| {
+            match lua.globals().get::<Option<LuaTable>>("PARAMETERS")? {
+                Some(parameters) => parameters.get::<LuaValue>(name),
+                None => Ok(LuaNil),
+            }
+        })?)?;
+        defaults.set_metatable(Some(metatable));
+        ctx.globals().set("PARAMS", defaults)?;
+
+        sandbox::reset_budget(&ctx);
+        ctx.load(script).set_name(format!("={}", name)).exec()?;
         let globals = ctx.globals();
 
         let mut functions = HashMap::<String, LuaFunction>::new();
@@ -373,80 +804,630 @@ impl LuaExercise {
         // Obtain required joints
         let required_joints = globals.get::<Vec<String>>("JOINTS")?;
 
-        Ok(Self {
-            ctx, 
-            name,
-            description,
-            repetitions_target, 
-            required_joints,
-            current_state: "entry".to_string(),
-            accumulated_warnings: HashMap::new(),
-            frames: vec![],
-            repetitions: 0, 
-            functions,
-        })
+        Ok((ctx, functions, required_joints))
+    }
+
+    /// Use a different set of control factors
+    pub fn with_registry(mut self, registry: Arc<ControlFactorRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Prepare the keypoints with the confidence threshold, missing joint policy and filter
+    pub fn with_preprocessing(mut self, preprocessing: Preprocessing) -> Self {
+        self.preprocessing = preprocessing;
+        self
+    }
+
+    /// Complete the exercise following the criteria instead of the repetitions,
+    /// fails if the criteria refers to a state the script does not have
+    pub fn with_completion(mut self, criteria: CompletionCriteria) -> Result<Self, MotionError> {
+        if let Some(state) = criteria.state().filter(|s| *s == "setup" || !self.functions.contains_key(*s)) {
+            return Err(MotionError::UnknownCompletionState { exercise: self.name.clone(), state: state.to_owned() });
+        }
+        self.completion = Completion::new(criteria);
+        Ok(self)
+    }
+
+    /// Run the exercise with the parameters of the session, the missing ones keep the default of the script.
+    /// Fails if a parameter is not declared by the script or has a different type than its default
+    pub fn with_parameters(self, parameters: &Parameters) -> Result<Self, MotionError> {
+        let resolved = parameters::resolve(&self.declared_parameters, parameters)
+            .map_err(|source| MotionError::InvalidParameter { exercise: self.name.clone(), source: Box::new(source) })?;
+        self.ctx.globals().set("PARAMS", resolved)
+            .map_err(|source| MotionError::Load { exercise: self.name.clone(), source: Box::new(source) })?;
+        Ok(self)
+    }
+
+    /// Parameters declared by the script with their defaults
+    pub fn declared_parameters(&self) -> &Parameters {
+        &self.declared_parameters
     }
 
     /// Convert a normal skeleton to a Lua table
     fn convert_skeleton(&self, skeleton: &Skeleton) -> LuaSkeleton {
         skeleton.iter()
-            .map(|(k, v): (&String, &Vec2)| {
-                (k.clone(), (*v).into())
+            .map(|(k, v): (&String, &Joint)| {
+                (k.clone(), v.position.into())
             })
             .collect()
     }
 
-    /// Change current state based on the current state output
-    fn update_current_state(&mut self, output: &StateOutput) {
+    /// Change current state based on the current state output,
+    /// the ON_EXIT hook of the old state and the ON_ENTER hook of the new one receive the frame
+    fn update_current_state(&mut self, output: &StateOutput, frame: &LuaTable, time: f32) -> Result<(), MotionError> {
         if let Some(next_state) = &output.next_state {
-            self.current_state = next_state.clone();
+            if !self.functions.contains_key(next_state) {
+                return Err(MotionError::UnknownState {
+                    exercise: self.name.clone(),
+                    state: self.current_state.clone(),
+                    next_state: next_state.clone(),
+                });
+            }
+            let previous_state = std::mem::replace(&mut self.current_state, next_state.clone());
+
+            {
+                let mut timing = self.ctx.app_data_mut::<Timing>().expect("missing timing");
+                timing.state_since = time;
+                timing.holds.clear();
+            }
+
+            self.call_hook(Some("ON_EXIT"), &previous_state, (frame, next_state.as_str()))?;
+            self.call_hook(Some("ON_ENTER"), next_state, (frame, previous_state.as_str()))?;
         }
+        Ok(())
     }
 
     /// Change number of repetitions done base on the current state output
-    fn update_repetitions(&mut self, output: &StateOutput) {
-        if output.metadata.events.iter().any(|v| *v == StateEvent::Repetition) {
+    /// and add the last stored frame to the current repetition, 'on_repetition' receives the number
+    /// of repetitions done and the frame
+    fn update_repetitions(&mut self, output: &StateOutput, frame: &LuaTable, time: f32) -> Result<(), MotionError> {
+        let index = self.frames.len() - 1;
+        self.repetition_log.add_frame(index, time, &self.current_state, output);
+        if output.metadata.events.contains(&StateEvent::Repetition) {
             self.repetitions += 1;
+            self.call_hook(None, "on_repetition", (self.repetitions, frame))?;
         }
+        Ok(())
     }
 
-    /// Accumulate warnings during the execution
-    fn update_warnings(&mut self, output: &StateOutput) {
-        for warning in &output.metadata.warnings {
-            self.accumulated_warnings.entry(warning.name.clone())
-                .and_modify(|acc| { *acc += 1 });
+    /// Invoke an optional hook of the script, nothing happens if the script does not define it.
+    /// The hook is a global function or, with a table, the function of the table with the given name
+    fn call_hook(&self, table: Option<&str>, name: &str, args: impl IntoLuaMulti) -> Result<(), MotionError> {
+        let hook_error = |source| MotionError::Hook {
+            exercise: self.name.clone(),
+            hook: table.map_or(name.to_owned(), |t| format!("{}.{}", t, name)),
+            source: Box::new(source),
+        };
+
+        let globals = self.ctx.globals();
+        let function = match table {
+            Some(table) => match globals.get::<Option<LuaTable>>(table).map_err(hook_error)? {
+                Some(table) => table.get::<Option<LuaFunction>>(name).map_err(hook_error)?,
+                None => None,
+            },
+            None => globals.get::<Option<LuaFunction>>(name).map_err(hook_error)?,
+        };
+
+        if let Some(function) = function {
+            sandbox::reset_budget(&self.ctx);
+            function.call::<()>(args).map_err(hook_error)?;
         }
+        Ok(())
+    }
+
+    /// Information about the exercise passed to 'setup' and 'teardown'
+    fn context_table(&self) -> LuaResult<LuaTable> {
+        let context = self.ctx.create_table()?;
+        context.set("name", self.name.as_str())?;
+        context.set("description", self.description.as_str())?;
+        context.set("repetitions_target", self.repetitions_target)?;
+        context.set("repetitions", self.repetitions)?;
+        context.set("parameters", self.ctx.globals().get::<LuaValue>("PARAMS")?)?;
+        Ok(context)
+    }
+
+    /// Invoke the 'setup' function of the script with the exercise context, only the first time.
+    /// Called before the first frame if not invoked explicitly, after the parameters are set
+    pub fn setup(&mut self) -> Result<(), MotionError> {
+        if self.set_up {
+            return Ok(());
+        }
+        self.set_up = true;
+
+        let hook_error = |source| MotionError::Hook { exercise: self.name.clone(), hook: "setup".to_owned(), source: Box::new(source) };
+        let context = self.context_table().map_err(hook_error)?;
+        sandbox::reset_budget(&self.ctx);
+        self.functions["setup"].call::<()>(context).map_err(hook_error)
     }
 
     /// Save current frame data and metadata for later storage
-    fn store(&mut self, skeleton: &Skeleton, output: &StateOutput) {
-        self.frames.push((skeleton.clone(), output.clone()));
+    fn store(&mut self, skeleton: &Skeleton, raw_skeleton: &Skeleton, output: &StateOutput, index: u64, time: f32) {
+        let raw_skeleton = (raw_skeleton != skeleton).then(|| raw_skeleton.clone());
+        self.frames.push(Frame { index, time, skeleton: skeleton.clone(), raw_skeleton, output: output.clone() });
     }
 
-    /// Handle a skeleton from the HPE, returns true when the exercise is complete and state output if the exercise did run.
-    pub fn process(&mut self, skeleton: &Skeleton) -> LuaResult<(bool, Option<StateOutput>)> {
-        let state_fn = self.functions.get(&self.current_state)
-            .expect("Invalid current state!");
-    
+    /// Handle a skeleton from the HPE with the frame index and capture time in seconds,
+    /// returns true when the exercise is complete and state output if the exercise did run.
+    pub fn process(&mut self, raw_skeleton: &Skeleton, index: u64, time: f32) -> Result<(bool, Option<StateOutput>), MotionError> {
+        self.setup()?;
+
         // If any required joint is missing from the frame skeleton, skip processing
-        if self.required_joints.iter().any(|j| !skeleton.contains_key(j)) {
-            let missing: Vec<String> = self.required_joints.iter().cloned().filter(|j| !skeleton.contains_key(j)).collect();
-            let available: Vec<String> = skeleton.keys().into_iter().cloned().collect();
-            println!("missing: {:?}, available: {:?}", missing, available);
+        let Some(skeleton) = self.preprocessing.apply(raw_skeleton, &self.required_joints, time) else {
             return Ok((false, None));
+        };
+        let skeleton = &skeleton;
+        let state_fn = self.functions.get(&self.current_state)
+            .ok_or_else(|| MotionError::InvalidCurrentState { exercise: self.name.clone(), state: self.current_state.clone() })?;
+        let runtime_error = |source| MotionError::Runtime {
+            exercise: self.name.clone(),
+            state: self.current_state.clone(),
+            source: Box::new(source),
+        };
+
+        // Update timing for the helpers, the first state starts with the first evaluated frame
+        {
+            let mut timing = self.ctx.app_data_mut::<Timing>().expect("missing timing");
+            timing.time = time;
+            if self.frames.is_empty() {
+                timing.state_since = time;
+            }
+        }
+
+        self.ctx.app_data_mut::<History>().expect("missing history").push(time, skeleton.clone());
+
+        // Time information of the frame, dt is zero on the first evaluated frame
+        let dt = self.frames.last().map_or(0.0, |f| time - f.time);
+        let frame = self.ctx.create_table().map_err(runtime_error)?;
+        frame.set("index", index).map_err(runtime_error)?;
+        frame.set("time", time).map_err(runtime_error)?;
+        frame.set("dt", dt).map_err(runtime_error)?;
+
+        // The first state is entered with the first evaluated frame
+        if self.frames.is_empty() {
+            self.call_hook(Some("ON_ENTER"), &self.current_state, (&frame, LuaNil))?;
         }
 
-        // Evaluate current frame
+        // Evaluate current frame, the state function receives the skeleton, all the available control factors
+        // and the frame timing
         let lua_skeleton = self.convert_skeleton(skeleton);
-        let output = state_fn.call::<StateOutput>(lua_skeleton)?;
-        self.store(skeleton, &output);
+        let factors = self.registry.compute(skeleton);
+        sandbox::reset_budget(&self.ctx);
+        let value = state_fn.call::<LuaValue>((lua_skeleton, factors, &frame)).map_err(runtime_error)?;
+        let output = StateOutput::from_lua(value, &self.ctx).map_err(|e| match e.downcast_ref::<InvalidValue>() {
+            Some(invalid) => MotionError::InvalidOutput {
+                exercise: self.name.clone(),
+                state: self.current_state.clone(),
+                source: Box::new(InvalidValue {
+                    field: if invalid.field.is_empty() { "return value".to_owned() } else { invalid.field.clone() },
+                    ..invalid.clone()
+                }),
+            },
+            None => runtime_error(e),
+        })?;
+        self.store(skeleton, raw_skeleton, &output, index, time);
 
-        self.update_current_state(&output);
-        self.update_repetitions(&output);
-        self.update_warnings(&output);
+        self.update_repetitions(&output, &frame, time)?;
+        self.completion.update(time, &self.current_state, &output.metadata.events);
+        self.update_current_state(&output, &frame, time)?;
 
-        let completed = self.repetitions >= self.repetitions_target;
+        let completed = self.completion.is_complete(self.repetitions, self.repetitions_target);
         Ok((completed, Some(output)))
     }
 
 }
+
+impl Exercise for LuaExercise {
+    fn process(&mut self, skeleton: &Skeleton, index: u64, time: f32) -> Result<(bool, Option<StateOutput>), MotionError> {
+        LuaExercise::process(self, skeleton, index, time)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn repetitions(&self) -> (u32, u32) {
+        (self.repetitions, self.repetitions_target)
+    }
+
+    fn repetition_log(&self) -> &RepetitionLog {
+        &self.repetition_log
+    }
+
+    fn current_state(&self) -> &str {
+        &self.current_state
+    }
+
+    fn required_joints(&self) -> &[String] {
+        &self.required_joints
+    }
+
+    fn missing_joints(&self) -> &[String] {
+        self.preprocessing.missing()
+    }
+
+    fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    fn completion(&self) -> &Completion {
+        &self.completion
+    }
+
+    fn setup(&mut self) -> Result<(), MotionError> {
+        LuaExercise::setup(self)
+    }
+
+    fn pause(&mut self) -> Result<(), MotionError> {
+        self.call_hook(None, "on_pause", ())
+    }
+
+    fn resume(&mut self) -> Result<(), MotionError> {
+        self.call_hook(None, "on_resume", ())
+    }
+
+    fn teardown(&mut self) -> Result<(), MotionError> {
+        if self.torn_down || !self.set_up {
+            return Ok(());
+        }
+        self.torn_down = true;
+
+        let context = self.context_table()
+            .map_err(|source| MotionError::Hook { exercise: self.name.clone(), hook: "teardown".to_owned(), source: Box::new(source) })?;
+        self.call_hook(None, "teardown", context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a script from its code, the session parameters are applied
+    fn load(script: &str, parameters: Parameters) -> LuaExercise {
+        LuaExercise::from_string(script.to_owned(), "test".to_owned(), String::new(), 3)
+            .and_then(|e| e.with_parameters(&parameters))
+            .unwrap()
+    }
+
+    /// Error of the first frame processed by a state function with the given body
+    fn process_error(body: &str) -> MotionError {
+        let script = format!("JOINTS = {{}}\nSTATES = {{}}\nfunction setup(context) end\nfunction entry(skeleton)\n{body}\nend");
+        load(&script, Parameters::new()).process(&Skeleton::new(), 0, 0.0).unwrap_err()
+    }
+
+    /// Skeleton with only the left wrist, at the given height
+    fn wrist_at(y: f32) -> Skeleton {
+        Skeleton::from([("left_wrist".to_owned(), Joint::from(Vec2::new(0.0, y)))])
+    }
+
+    /// Calls recorded by the hooks of the script in the global CALLS
+    fn calls(exercise: &LuaExercise) -> Vec<String> {
+        exercise.ctx.globals().get("CALLS").unwrap()
+    }
+
+    /// Value of a delta key of the output
+    fn delta(output: &StateOutput, key: &str) -> f32 {
+        output.metadata.delta.as_ref().expect("missing delta")[key]
+    }
+
+    #[test]
+    fn parameters_are_available_to_the_top_level_of_the_script() {
+        let script = r#"
+            JOINTS = {}
+            STATES = {}
+            PARAMETERS = { work_angle = 110.0 }
+            THRESHOLD = PARAMS.work_angle
+            function setup(context) end
+            function entry(skeleton)
+                return stay({ delta = { threshold = THRESHOLD, work_angle = PARAMS.work_angle } })
+            end
+        "#;
+        let params = HashMap::from([("work_angle".to_owned(), ParameterValue::Number(120.0))]);
+        let mut exercise = load(script, params);
+        let (_, output) = exercise.process(&Skeleton::new(), 0, 0.0).unwrap();
+        let output = output.unwrap();
+
+        // The top level runs with the defaults, the state functions with the values of the session
+        assert_eq!(delta(&output, "threshold"), 110.0);
+        assert_eq!(delta(&output, "work_angle"), 120.0);
+    }
+
+    /// Field path and message of an invalid state output
+    fn invalid_output(error: MotionError) -> (String, String) {
+        let message = error.to_string();
+        match error {
+            MotionError::InvalidOutput { source, .. } => (source.field, message),
+            other => panic!("expected an invalid output, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_event_reports_its_position() {
+        let (field, message) = invalid_output(process_error("return stay({ events = { 42 } })"));
+        assert_eq!(field, "metadata.events[1]");
+        assert_eq!(message, "exercise 'test', state 'entry': invalid value 42 for field 'metadata.events[1]', expected an event name or an event table");
+    }
+
+    #[test]
+    fn invalid_nested_widget_field_reports_the_whole_path() {
+        let (field, message) = invalid_output(process_error(
+            "return stay({ widgets = { { widget = 'circle', position = { x = 1, y = 'up' }, text = 'a' } } })"));
+        assert_eq!(field, "metadata.widgets[1].position.y");
+        assert_eq!(message, "exercise 'test', state 'entry': invalid value \"up\" for field 'metadata.widgets[1].position.y', expected a number");
+    }
+
+    #[test]
+    fn invalid_return_value_is_reported_as_such() {
+        let (field, message) = invalid_output(process_error("return 42"));
+        assert_eq!(field, "return value");
+        assert_eq!(message, "exercise 'test', state 'entry': invalid value 42 for field 'return value', expected the result of 'stay' or 'step'");
+    }
+
+    #[test]
+    fn invalid_step_argument_is_a_runtime_error() {
+        let error = process_error("return step('next', 5)");
+        let message = error.to_string();
+        assert!(matches!(error, MotionError::Runtime { ref state, .. } if state == "entry"), "{error:?}");
+        assert!(message.starts_with("exercise 'test', state 'entry': bad argument #2"), "{message}");
+        assert!(message.contains("in function 'step'"), "{message}");
+    }
+
+    /// Exercise raising the left wrist, every hook records its call with its arguments
+    const HOOKS_SCRIPT: &str = r#"
+        JOINTS = { "left_wrist" }
+        STATES = { "up" }
+        CALLS = {}
+        local function record(...)
+            local args = {}
+            for i = 1, select('#', ...) do args[i] = tostring((select(i, ...))) end
+            CALLS[#CALLS + 1] = table.concat(args, " ")
+        end
+
+        function setup(context) record("setup", context.name, context.repetitions_target) end
+        function teardown(context) record("teardown", context.repetitions) end
+        function on_repetition(count, frame) record("on_repetition", count, frame.index) end
+        function on_pause() record("on_pause") end
+        function on_resume() record("on_resume") end
+        ON_ENTER = {
+            entry = function(frame, previous) record("ON_ENTER.entry", frame.index, previous) end,
+            up = function(frame, previous) record("ON_ENTER.up", frame.index, previous) end,
+        }
+        ON_EXIT = {
+            up = function(frame, next) record("ON_EXIT.up", frame.index, next) end,
+        }
+
+        function entry(skeleton)
+            if skeleton.left_wrist.y < 0 then return step("up") end
+            return stay()
+        end
+        function up(skeleton)
+            if skeleton.left_wrist.y >= 0 then return step("entry", { events = { "repetition" } }) end
+            return stay()
+        end
+    "#;
+
+    #[test]
+    fn hooks_run_in_order_with_their_arguments() {
+        let mut exercise = load(HOOKS_SCRIPT, Parameters::new());
+        exercise.process(&wrist_at(1.0), 0, 0.0).unwrap();
+        exercise.process(&wrist_at(-1.0), 1, 0.1).unwrap();
+        Exercise::pause(&mut exercise).unwrap();
+        Exercise::resume(&mut exercise).unwrap();
+        exercise.process(&wrist_at(-1.0), 2, 0.2).unwrap();
+        exercise.process(&wrist_at(1.0), 3, 0.3).unwrap();
+        Exercise::teardown(&mut exercise).unwrap();
+
+        // ON_EXIT.entry is not defined, the repetition is counted before the state changes
+        assert_eq!(calls(&exercise), [
+            "setup test 3",
+            "ON_ENTER.entry 0 nil",
+            "ON_ENTER.up 1 entry",
+            "on_pause",
+            "on_resume",
+            "on_repetition 1 3",
+            "ON_EXIT.up 3 entry",
+            "ON_ENTER.entry 3 up",
+            "teardown 1",
+        ]);
+    }
+
+    #[test]
+    fn teardown_runs_once_and_only_after_setup() {
+        let mut exercise = load(HOOKS_SCRIPT, Parameters::new());
+        Exercise::teardown(&mut exercise).unwrap();
+        assert!(calls(&exercise).is_empty());
+
+        Exercise::setup(&mut exercise).unwrap();
+        Exercise::setup(&mut exercise).unwrap();
+        Exercise::teardown(&mut exercise).unwrap();
+        Exercise::teardown(&mut exercise).unwrap();
+        assert_eq!(calls(&exercise), ["setup test 3", "teardown 0"]);
+    }
+
+    #[test]
+    fn undefined_hooks_are_skipped() {
+        let script = r#"
+            JOINTS = { "left_wrist" }
+            STATES = { "up" }
+            function setup(context) end
+            function entry(skeleton)
+                if skeleton.left_wrist.y < 0 then return step("up", { events = { "repetition" } }) end
+                return stay()
+            end
+            function up(skeleton) return stay() end
+        "#;
+        let mut exercise = load(script, Parameters::new());
+        exercise.process(&wrist_at(1.0), 0, 0.0).unwrap();
+        Exercise::pause(&mut exercise).unwrap();
+        Exercise::resume(&mut exercise).unwrap();
+        exercise.process(&wrist_at(-1.0), 1, 0.1).unwrap();
+        Exercise::teardown(&mut exercise).unwrap();
+        assert_eq!(exercise.current_state(), "up");
+        assert_eq!(exercise.repetitions(), (1, 3));
+    }
+
+    #[test]
+    fn hook_errors_name_the_hook() {
+        let script = HOOKS_SCRIPT.replace(r#"record("ON_ENTER.up", frame.index, previous)"#, r#"error("broken")"#);
+        let mut exercise = load(&script, Parameters::new());
+        exercise.process(&wrist_at(1.0), 0, 0.0).unwrap();
+        let error = exercise.process(&wrist_at(-1.0), 1, 0.1).unwrap_err();
+        assert!(matches!(error, MotionError::Hook { ref hook, .. } if hook == "ON_ENTER.up"), "{error:?}");
+        assert!(error.to_string().contains("broken"), "{error}");
+    }
+
+    /// Event converted from the Lua code of a value
+    fn event(code: &str) -> LuaResult<StateEvent> {
+        let lua = Lua::new();
+        StateEvent::from_lua(lua.load(code).eval()?, &lua)
+    }
+
+    #[test]
+    fn event_names_are_parsed() {
+        assert_eq!(StateEvent::from_name("phase:raising"), StateEvent::Phase { name: "raising".to_owned() });
+        assert_eq!(StateEvent::from_name("Phase:Raising"), StateEvent::Phase { name: "Raising".to_owned() });
+        assert_eq!(StateEvent::from_name("half_repetition"), StateEvent::PartialRepetition { progress: 0.5 });
+        assert_eq!(StateEvent::from_name("partial_repetition"), StateEvent::PartialRepetition { progress: 0.5 });
+        assert_eq!(StateEvent::from_name("REPETITION"), StateEvent::Repetition);
+        assert_eq!(StateEvent::from_name("start"), StateEvent::Start);
+        assert_eq!(StateEvent::from_name("complete"), StateEvent::Complete);
+
+        // Names that are not built-in are custom events, a colon alone is not a phase
+        assert_eq!(StateEvent::from_name("side_switch"), StateEvent::Custom { name: "side_switch".to_owned(), payload: None });
+        assert_eq!(StateEvent::from_name("side:left"), StateEvent::Custom { name: "side:left".to_owned(), payload: None });
+        assert_eq!(StateEvent::from_name("phase"), StateEvent::Custom { name: "phase".to_owned(), payload: None });
+    }
+
+    #[test]
+    fn event_tables_are_converted() {
+        assert_eq!(event(r#"{ name = "phase", phase = "lowering" }"#).unwrap(), StateEvent::Phase { name: "lowering".to_owned() });
+        assert_eq!(event(r#"{ name = "partial_repetition", progress = 0.25 }"#).unwrap(), StateEvent::PartialRepetition { progress: 0.25 });
+        assert_eq!(event(r#"{ name = "half_repetition" }"#).unwrap(), StateEvent::PartialRepetition { progress: 0.5 });
+        assert_eq!(event(r#"{ name = "repetition" }"#).unwrap(), StateEvent::Repetition);
+        assert_eq!(event(r#"{ name = "side_switch", payload = { side = 1 } }"#).unwrap(), StateEvent::Custom {
+            name: "side_switch".to_owned(),
+            payload: Some(HashMap::from([("side".to_owned(), 1.0)])),
+        });
+        assert_eq!(event(r#"{ name = "hold_reached" }"#).unwrap(), StateEvent::Custom { name: "hold_reached".to_owned(), payload: None });
+    }
+
+    #[test]
+    fn invalid_event_tables_report_the_field() {
+        for (code, field) in [
+            (r#"{ name = "phase" }"#, "phase"),
+            (r#"{ name = "partial_repetition", progress = "half" }"#, "progress"),
+            (r#"{ name = "side_switch", payload = 1 }"#, "payload"),
+            (r#"{ progress = 0.5 }"#, "name"),
+            ("42", ""),
+        ] {
+            let error = event(code).unwrap_err();
+            let invalid = error.downcast_ref::<InvalidValue>().unwrap_or_else(|| panic!("{code}: {error}"));
+            assert_eq!(invalid.field, field, "{code}");
+        }
+    }
+
+    #[test]
+    fn only_repetition_events_complete_a_repetition() {
+        let script = r#"
+            JOINTS = {}
+            STATES = {}
+            EVENTS = {
+                "phase:raising",
+                { name = "partial_repetition", progress = 0.5 },
+                { name = "side_switch", payload = { side = 1 } },
+                "repetition",
+                "phase:lowering",
+            }
+            function setup(context) end
+            function entry(skeleton, factors, frame)
+                return stay({ events = { EVENTS[frame.index + 1] } })
+            end
+        "#;
+        let mut exercise = load(script, Parameters::new());
+        for index in 0..3 {
+            exercise.process(&Skeleton::new(), index, index as f32).unwrap();
+        }
+        assert!(exercise.repetition_log().completed().is_empty());
+        assert_eq!(exercise.repetition_log().current().unwrap().frames, 0..3);
+        assert_eq!(exercise.repetitions(), (0, 3));
+
+        for index in 3..5 {
+            exercise.process(&Skeleton::new(), index, index as f32).unwrap();
+        }
+        let log = exercise.repetition_log();
+        assert_eq!(log.completed().len(), 1);
+        assert_eq!(log.completed()[0].frames, 0..4);
+        assert_eq!(log.completed()[0].duration(), 3.0);
+        assert_eq!(log.current().unwrap().frames, 4..5);
+        assert_eq!(exercise.repetitions(), (1, 3));
+
+        // The events are stored with the frames
+        assert_eq!(exercise.frames()[1].output.metadata.events, [StateEvent::PartialRepetition { progress: 0.5 }]);
+        assert_eq!(exercise.frames()[4].output.metadata.events, [StateEvent::Phase { name: "lowering".to_owned() }]);
+    }
+
+    /// Exercise reporting the timing helpers in the delta, the state changes when the left wrist moves right
+    const TIMING_SCRIPT: &str = r#"
+        JOINTS = { "left_wrist" }
+        STATES = { "up" }
+        function setup(context) end
+        local function evaluate(skeleton, next_state)
+            local held, held_for = hold("raised", skeleton.left_wrist.y < 0, 1.0)
+            local delta = { state_time = state_time(), held = held and 1 or 0, held_for = held_for }
+            if next_state then return step(next_state, { delta = delta }) end
+            return stay({ delta = delta })
+        end
+        function entry(skeleton) return evaluate(skeleton, skeleton.left_wrist.x > 0 and "up" or nil) end
+        function up(skeleton) return evaluate(skeleton) end
+    "#;
+
+    /// Process the left wrist at the (time, x, y) samples and collect the values of the delta keys
+    fn timing(samples: &[(f32, f32, f32)], keys: &[&str]) -> Vec<Vec<f32>> {
+        let mut exercise = load(TIMING_SCRIPT, Parameters::new());
+        samples.iter().enumerate()
+            .map(|(index, (time, x, y))| {
+                let skeleton = Skeleton::from([("left_wrist".to_owned(), Joint::from(Vec2::new(*x, *y)))]);
+                let (_, output) = exercise.process(&skeleton, index as u64, *time).unwrap();
+                let output = output.unwrap();
+                keys.iter().map(|key| delta(&output, key)).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hold_resets_when_the_condition_drops() {
+        let samples = [(0.0, 0.0, -1.0), (0.5, 0.0, -1.0), (1.0, 0.0, -1.0), (1.5, 0.0, 1.0), (2.0, 0.0, -1.0), (2.5, 0.0, -1.0)];
+        assert_eq!(timing(&samples, &["held_for", "held"]), [
+            [0.0, 0.0], [0.5, 0.0], [1.0, 1.0], [0.0, 0.0], [0.0, 0.0], [0.5, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn hold_resets_when_the_state_changes() {
+        // The second frame moves to 'up', the condition is still satisfied
+        let samples = [(0.0, 0.0, -1.0), (1.0, 1.0, -1.0), (1.5, 1.0, -1.0), (2.0, 1.0, -1.0)];
+        assert_eq!(timing(&samples, &["held_for", "held"]), [
+            [0.0, 0.0], [1.0, 1.0], [0.0, 0.0], [0.5, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn state_time_counts_from_the_entry_in_the_state() {
+        // The first state starts with the first frame, 'up' with the frame that moved to it
+        let samples = [(10.0, 0.0, 1.0), (11.0, 0.0, 1.0), (12.0, 1.0, 1.0), (12.5, 1.0, 1.0), (13.0, 1.0, 1.0)];
+        assert_eq!(timing(&samples, &["state_time"]), [[0.0], [1.0], [2.0], [0.5], [1.0]]);
+    }
+
+    #[test]
+    fn formats_are_recognized_by_extension() {
+        assert_eq!(ExerciseFormat::from_extension("lua"), Some(ExerciseFormat::Lua));
+        assert_eq!(ExerciseFormat::from_extension("fsm"), Some(ExerciseFormat::Json));
+        assert_eq!(ExerciseFormat::from_extension("JSON"), Some(ExerciseFormat::Json));
+        assert_eq!(ExerciseFormat::from_extension("yml"), Some(ExerciseFormat::Yaml));
+        assert_eq!(ExerciseFormat::from_extension("txt"), None);
+    }
+}
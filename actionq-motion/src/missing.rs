@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::{Joint, Skeleton};
+
+/// What to do when a joint required by the exercise is missing from a frame,
+/// for example because the patient stepped partly out of view
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MissingJointPolicy {
+    /// The frame is not evaluated
+    #[default]
+    Skip,
+    /// The last known position of the joint is used for at most the given number of frames
+    Hold {
+        #[serde(default = "default_frames")]
+        frames: u32,
+    },
+    /// The joint keeps moving with the velocity it had when it was last seen, for at most the given number of frames.
+    /// The following frames are not available yet, so the position is extrapolated from the previous ones
+    Interpolate {
+        #[serde(default = "default_frames")]
+        frames: u32,
+    },
+}
+
+fn default_frames() -> u32 { 5 }
+
+impl MissingJointPolicy {
+    /// For how many consecutive frames a missing joint can be replaced
+    fn max_frames(&self) -> u32 {
+        match self {
+            Self::Skip => 0,
+            Self::Hold { frames } | Self::Interpolate { frames } => *frames,
+        }
+    }
+}
+
+/// Last observation of a joint
+#[derive(Debug, Clone, Copy)]
+struct LastSeen {
+    /// Capture time of the frame, in seconds
+    time: f32,
+    joint: Joint,
+    /// Velocity between the last two observations, in units per second
+    velocity: Vec2,
+    /// Consecutive frames in which the joint was replaced
+    replaced: u32,
+}
+
+/// Replaces the required joints missing from a frame following the policy of the exercise
+#[derive(Debug, Clone, Default)]
+pub struct JointRecovery {
+    policy: MissingJointPolicy,
+    last_seen: HashMap<String, LastSeen>,
+    /// Required joints missing from the last frame that could not be replaced
+    missing: Vec<String>,
+}
+
+impl JointRecovery {
+
+    pub fn new(policy: MissingJointPolicy) -> Self {
+        Self { policy, ..Default::default() }
+    }
+
+    /// Policy used to replace the joints
+    pub fn policy(&self) -> MissingJointPolicy {
+        self.policy
+    }
+
+    /// Required joints missing from the last frame that could not be replaced, the frame must not be evaluated
+    pub fn missing(&self) -> &[String] {
+        &self.missing
+    }
+
+    /// Add the required joints missing from the skeleton when the policy allows it,
+    /// returns true if all the required joints are available
+    pub fn recover(&mut self, skeleton: &mut Skeleton, required_joints: &[String], time: f32) -> bool {
+        self.missing.clear();
+        for name in required_joints {
+            match (skeleton.get(name), self.last_seen.get_mut(name)) {
+                (Some(joint), last) => {
+                    let velocity = match last {
+                        Some(last) if time > last.time => (joint.position - last.joint.position) / (time - last.time),
+                        _ => Vec2::ZERO,
+                    };
+                    self.last_seen.insert(name.clone(), LastSeen { time, joint: *joint, velocity, replaced: 0 });
+                },
+                (None, Some(last)) if last.replaced < self.policy.max_frames() => {
+                    last.replaced += 1;
+                    let position = match self.policy {
+                        MissingJointPolicy::Interpolate { .. } => last.joint.position + last.velocity * (time - last.time),
+                        _ => last.joint.position,
+                    };
+                    skeleton.insert(name.clone(), Joint { position, ..last.joint });
+                },
+                (None, _) => self.missing.push(name.clone()),
+            }
+        }
+        self.missing.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn required() -> Vec<String> {
+        vec!["left_wrist".to_owned()]
+    }
+
+    /// Skeleton with the left wrist at the position and the neck, None without the wrist
+    fn skeleton(wrist: Option<(f32, f32)>) -> Skeleton {
+        let mut skeleton = Skeleton::from([("neck".to_owned(), Vec2::new(0.0, 0.0).into())]);
+        if let Some((x, y)) = wrist {
+            skeleton.insert("left_wrist".to_owned(), Joint::new(Vec2::new(x, y), 0.8));
+        }
+        skeleton
+    }
+
+    /// Recover the frames at 10 frames per second, returns the position of the wrist in each frame
+    fn run(policy: MissingJointPolicy, frames: &[Option<(f32, f32)>]) -> Vec<Option<Vec2>> {
+        let mut recovery = JointRecovery::new(policy);
+        frames.iter().enumerate()
+            .map(|(i, wrist)| {
+                let mut skeleton = skeleton(*wrist);
+                let complete = recovery.recover(&mut skeleton, &required(), i as f32 / 10.0);
+                assert_eq!(complete, recovery.missing().is_empty());
+                complete.then(|| skeleton["left_wrist"].position)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skip_never_replaces_the_joints() {
+        let positions = run(MissingJointPolicy::Skip, &[Some((10.0, 0.0)), None, Some((30.0, 0.0))]);
+        assert_eq!(positions, [Some(Vec2::new(10.0, 0.0)), None, Some(Vec2::new(30.0, 0.0))]);
+    }
+
+    #[test]
+    fn hold_keeps_the_last_position_for_the_frames() {
+        let positions = run(MissingJointPolicy::Hold { frames: 2 }, &[Some((10.0, 0.0)), Some((20.0, 5.0)), None, None, None]);
+        assert_eq!(positions[2], Some(Vec2::new(20.0, 5.0)));
+        assert_eq!(positions[3], Some(Vec2::new(20.0, 5.0)));
+        assert_eq!(positions[4], None);
+    }
+
+    #[test]
+    fn interpolate_keeps_the_last_velocity_for_the_frames() {
+        let positions = run(MissingJointPolicy::Interpolate { frames: 2 }, &[Some((10.0, 0.0)), Some((20.0, 5.0)), None, None, None]);
+        assert!((positions[2].unwrap() - Vec2::new(30.0, 10.0)).length() < EPSILON);
+        assert!((positions[3].unwrap() - Vec2::new(40.0, 15.0)).length() < EPSILON);
+        assert_eq!(positions[4], None);
+    }
+
+    #[test]
+    fn replaced_frames_are_counted_again_after_the_joint_is_seen() {
+        let frames = [Some((10.0, 0.0)), None, Some((10.0, 0.0)), None, Some((10.0, 0.0))];
+        let positions = run(MissingJointPolicy::Hold { frames: 1 }, &frames);
+        assert!(positions.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn joints_never_seen_cannot_be_replaced() {
+        let mut recovery = JointRecovery::new(MissingJointPolicy::Hold { frames: 5 });
+        let mut skeleton = skeleton(None);
+        assert!(!recovery.recover(&mut skeleton, &required(), 0.0));
+        assert_eq!(recovery.missing(), ["left_wrist"]);
+        assert!(!skeleton.contains_key("left_wrist"));
+    }
+
+    #[test]
+    fn replaced_joints_keep_their_confidence() {
+        let mut recovery = JointRecovery::new(MissingJointPolicy::Hold { frames: 1 });
+        recovery.recover(&mut skeleton(Some((10.0, 0.0))), &required(), 0.0);
+        let mut skeleton = skeleton(None);
+        recovery.recover(&mut skeleton, &required(), 0.1);
+        assert_eq!(skeleton["left_wrist"].confidence, 0.8);
+    }
+
+    #[test]
+    fn policies_are_parsed_with_default_frames() {
+        let policy: MissingJointPolicy = serde_json::from_str(r#"{"type": "hold"}"#).unwrap();
+        assert_eq!(policy, MissingJointPolicy::Hold { frames: 5 });
+        let policy: MissingJointPolicy = serde_json::from_str(r#"{"type": "interpolate", "frames": 10}"#).unwrap();
+        assert_eq!(policy, MissingJointPolicy::Interpolate { frames: 10 });
+        assert_eq!(MissingJointPolicy::default(), MissingJointPolicy::Skip);
+    }
+}
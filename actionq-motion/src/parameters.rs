@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Parameters of an exercise by name
+pub type Parameters = HashMap<String, ParameterValue>;
+
+/// Value of a parameter of an exercise, for example a target angle or the side to train
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParameterValue {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+impl ParameterValue {
+    /// Name of the type of the value
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Bool(_) => "boolean",
+            Self::Number(_) => "number",
+            Self::Text(_) => "string",
+        }
+    }
+}
+
+impl std::fmt::Display for ParameterValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Number(value) => write!(f, "{}", value),
+            Self::Text(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+impl FromLua for ParameterValue {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Boolean(value) => Ok(Self::Bool(value)),
+            LuaValue::Integer(value) => Ok(Self::Number(value as f64)),
+            LuaValue::Number(value) => Ok(Self::Number(value)),
+            LuaValue::String(value) => Ok(Self::Text(value.to_str()?.to_owned())),
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "ParameterValue".to_string(),
+                message: Some("a parameter must be a boolean, a number or a string".to_string()),
+            }),
+        }
+    }
+}
+
+impl IntoLua for ParameterValue {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        match self {
+            Self::Bool(value) => value.into_lua(lua),
+            Self::Number(value) => value.into_lua(lua),
+            Self::Text(value) => value.into_lua(lua),
+        }
+    }
+}
+
+/// Parameter of a session not accepted by the exercise
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ParameterError {
+    /// The script does not declare the parameter in PARAMETERS
+    #[error("unknown parameter '{0}'")]
+    Unknown(String),
+    /// The value has a different type than the default declared by the script
+    #[error("invalid value {value} for parameter '{name}', expected a {expected}")]
+    WrongType { name: String, value: ParameterValue, expected: &'static str },
+}
+
+/// Values of the parameters for a session: the declared defaults replaced by the given values.
+/// Every given parameter must be declared with a default of the same type
+pub fn resolve(declared: &Parameters, given: &Parameters) -> Result<Parameters, ParameterError> {
+    let mut resolved = declared.clone();
+    for (name, value) in given {
+        let default = declared.get(name)
+            .ok_or_else(|| ParameterError::Unknown(name.clone()))?;
+        if std::mem::discriminant(default) != std::mem::discriminant(value) {
+            return Err(ParameterError::WrongType {
+                name: name.clone(),
+                value: value.clone(),
+                expected: default.type_name(),
+            });
+        }
+        resolved.insert(name.clone(), value.clone());
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declared() -> Parameters {
+        HashMap::from([
+            ("work_angle".to_owned(), ParameterValue::Number(110.0)),
+            ("side".to_owned(), ParameterValue::Text("right".to_owned())),
+            ("count_half".to_owned(), ParameterValue::Bool(false)),
+        ])
+    }
+
+    #[test]
+    fn missing_parameters_keep_the_default() {
+        assert_eq!(resolve(&declared(), &Parameters::new()), Ok(declared()));
+    }
+
+    #[test]
+    fn given_parameters_replace_the_default() {
+        let given = HashMap::from([("work_angle".to_owned(), ParameterValue::Number(120.0))]);
+        let resolved = resolve(&declared(), &given).unwrap();
+        assert_eq!(resolved["work_angle"], ParameterValue::Number(120.0));
+        assert_eq!(resolved["side"], ParameterValue::Text("right".to_owned()));
+        assert_eq!(resolved.len(), 3);
+    }
+
+    #[test]
+    fn unknown_parameters_are_rejected() {
+        let given = HashMap::from([("tolerance".to_owned(), ParameterValue::Number(5.0))]);
+        assert_eq!(resolve(&declared(), &given), Err(ParameterError::Unknown("tolerance".to_owned())));
+    }
+
+    #[test]
+    fn parameters_of_another_type_are_rejected() {
+        let given = HashMap::from([("work_angle".to_owned(), ParameterValue::Text("120".to_owned()))]);
+        let error = resolve(&declared(), &given).unwrap_err();
+        assert_eq!(error, ParameterError::WrongType {
+            name: "work_angle".to_owned(),
+            value: ParameterValue::Text("120".to_owned()),
+            expected: "number",
+        });
+        assert_eq!(error.to_string(), "invalid value \"120\" for parameter 'work_angle', expected a number");
+
+        let given = HashMap::from([("count_half".to_owned(), ParameterValue::Number(1.0))]);
+        assert!(matches!(resolve(&declared(), &given), Err(ParameterError::WrongType { expected: "boolean", .. })));
+    }
+}
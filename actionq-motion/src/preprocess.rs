@@ -0,0 +1,94 @@
+use crate::{FilterConfig, JointRecovery, MissingJointPolicy, Skeleton, SkeletonFilter};
+
+/// Preparation of the skeletons received from the pose estimator before an exercise evaluates them,
+/// shared by all the exercise backends. The joints with a low confidence are removed, the missing
+/// required joints are replaced following the policy and then the keypoints are smoothed
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessing {
+    /// Joints with a lower confidence are considered missing
+    min_confidence: f32,
+    /// Replaces the missing required joints
+    recovery: JointRecovery,
+    /// Smoothing of the keypoints before the evaluation
+    filter: SkeletonFilter,
+}
+
+impl Preprocessing {
+
+    /// Smooth the keypoints before the evaluation
+    pub fn with_filter(mut self, filter: FilterConfig) -> Self {
+        self.filter = SkeletonFilter::new(filter);
+        self
+    }
+
+    /// Consider missing the joints with a confidence lower than the threshold
+    pub fn with_min_confidence(mut self, min_confidence: f32) -> Self {
+        self.min_confidence = min_confidence;
+        self
+    }
+
+    /// Replace the missing required joints following the policy
+    pub fn with_missing_joint_policy(mut self, policy: MissingJointPolicy) -> Self {
+        self.recovery = JointRecovery::new(policy);
+        self
+    }
+
+    /// Required joints missing from the last frame that could not be replaced
+    pub fn missing(&self) -> &[String] {
+        self.recovery.missing()
+    }
+
+    /// Prepare the skeleton captured at the given time in seconds for the evaluation.
+    /// Returns None if a required joint is missing and cannot be replaced, the frame must not be
+    /// evaluated and it is not added to the filter
+    pub fn apply(&mut self, raw_skeleton: &Skeleton, required_joints: &[String], time: f32) -> Option<Skeleton> {
+        let mut skeleton: Skeleton = raw_skeleton.iter()
+            .filter(|(_, j)| j.confidence >= self.min_confidence)
+            .map(|(k, j)| (k.clone(), *j))
+            .collect();
+
+        if !self.recovery.recover(&mut skeleton, required_joints, time) {
+            return None;
+        }
+        Some(self.filter.apply(&skeleton, time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec2;
+    use crate::Joint;
+
+    fn skeleton(joints: &[(&str, f32, f32)]) -> Skeleton {
+        joints.iter().map(|(k, x, confidence)| (k.to_string(), Joint::new(Vec2::new(*x, 0.0), *confidence))).collect()
+    }
+
+    fn required(joints: &[&str]) -> Vec<String> {
+        joints.iter().map(|j| j.to_string()).collect()
+    }
+
+    #[test]
+    fn joints_with_low_confidence_are_missing() {
+        let mut preprocessing = Preprocessing::default().with_min_confidence(0.5);
+        let raw = skeleton(&[("left_wrist", 10.0, 0.9), ("right_wrist", 20.0, 0.2)]);
+
+        let skeleton = preprocessing.apply(&raw, &required(&["left_wrist"]), 0.0).unwrap();
+        assert_eq!(skeleton.keys().collect::<Vec<_>>(), ["left_wrist"]);
+
+        assert!(preprocessing.apply(&raw, &required(&["right_wrist"]), 0.1).is_none());
+        assert_eq!(preprocessing.missing(), ["right_wrist"]);
+    }
+
+    #[test]
+    fn incomplete_frames_are_not_filtered() {
+        let mut preprocessing = Preprocessing::default().with_filter(FilterConfig::Ema { alpha: 0.5 });
+        let required = required(&["left_wrist", "right_wrist"]);
+        preprocessing.apply(&skeleton(&[("left_wrist", 0.0, 1.0), ("right_wrist", 0.0, 1.0)]), &required, 0.0);
+
+        // The left wrist of the skipped frame does not move the average
+        assert!(preprocessing.apply(&skeleton(&[("left_wrist", 100.0, 1.0)]), &required, 0.1).is_none());
+        let skeleton = preprocessing.apply(&skeleton(&[("left_wrist", 10.0, 1.0), ("right_wrist", 0.0, 1.0)]), &required, 0.2).unwrap();
+        assert_eq!(skeleton["left_wrist"].position, Vec2::new(5.0, 0.0));
+    }
+}
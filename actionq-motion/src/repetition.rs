@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::{Scoring, StateEvent, StateOutput};
+
+/// Minimum and maximum of a value during a repetition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Bounds {
+    fn new(value: f32) -> Self {
+        Self { min: value, max: value }
+    }
+
+    fn update(&mut self, value: f32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+/// Record of a single repetition of the exercise
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repetition {
+    /// When the repetition started, in seconds.
+    /// It is the end of the previous repetition, the start event or the first processed frame
+    pub start: f32,
+    /// When the repetition ended, in seconds
+    pub end: f32,
+    /// Position of the frames of the repetition in the frames of the exercise
+    pub frames: Range<usize>,
+    /// States visited during the repetition, in order
+    pub states: Vec<String>,
+    /// Number of frames in which each warning was raised
+    pub warnings: HashMap<String, u32>,
+    /// Minimum and maximum of each delta key
+    pub delta: HashMap<String, Bounds>,
+    /// Quality score from 0 to 100, set when the repetition is complete
+    pub score: f32,
+}
+
+impl Repetition {
+    fn new(start: f32, frame: usize) -> Self {
+        Self {
+            start,
+            end: start,
+            frames: frame..frame,
+            states: vec![],
+            warnings: HashMap::new(),
+            delta: HashMap::new(),
+            score: 100.0,
+        }
+    }
+
+    /// Duration of the repetition in seconds
+    pub fn duration(&self) -> f32 {
+        self.end - self.start
+    }
+
+    /// Add a processed frame, the state is the one that evaluated the frame
+    fn add_frame(&mut self, frame: usize, time: f32, state: &str, output: &StateOutput) {
+        self.end = time;
+        self.frames.end = frame + 1;
+
+        if self.states.last().map(String::as_str) != Some(state) {
+            self.states.push(state.to_owned());
+        }
+
+        // Repeated warnings are counted once per frame
+        let mut names: Vec<&str> = output.metadata.warnings.iter().map(|w| w.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            *self.warnings.entry(name.to_owned()).or_insert(0) += 1;
+        }
+
+        for (key, value) in output.metadata.delta.iter().flatten() {
+            self.delta.entry(key.clone())
+                .and_modify(|b| b.update(*value))
+                .or_insert_with(|| Bounds::new(*value));
+        }
+    }
+}
+
+/// Splits the processed frames of an exercise into repetitions
+#[derive(Debug, Clone, Default)]
+pub struct RepetitionLog {
+    /// Weights of the warnings used to score the repetitions
+    scoring: Scoring,
+    /// Completed repetitions
+    completed: Vec<Repetition>,
+    /// Repetition in progress
+    current: Option<Repetition>,
+}
+
+impl RepetitionLog {
+
+    /// Create an empty log, the repetitions are scored with the given weights
+    pub fn new(scoring: Scoring) -> Self {
+        Self { scoring, ..Default::default() }
+    }
+
+    /// Add a processed frame with its position in the frames of the exercise,
+    /// the state is the one that evaluated the frame.
+    /// The start event discards the frames before it, the repetition event completes the current repetition
+    pub fn add_frame(&mut self, frame: usize, time: f32, state: &str, output: &StateOutput) {
+        let events = &output.metadata.events;
+        if events.contains(&StateEvent::Start) {
+            self.current = Some(Repetition::new(time, frame));
+        }
+
+        let current = self.current.get_or_insert_with(|| Repetition::new(time, frame));
+        current.add_frame(frame, time, state, output);
+
+        if events.contains(&StateEvent::Repetition) {
+            let mut repetition = self.current.take().expect("missing current repetition");
+            repetition.score = self.scoring.score(&repetition.warnings, repetition.frames.len());
+            self.completed.push(repetition);
+
+            // The next repetition starts where this one ends
+            self.current = Some(Repetition::new(time, frame + 1));
+        }
+    }
+
+    /// Completed repetitions
+    pub fn completed(&self) -> &[Repetition] {
+        &self.completed
+    }
+
+    /// Repetition in progress, if any frame was processed after the last repetition
+    pub fn current(&self) -> Option<&Repetition> {
+        self.current.as_ref().filter(|r| !r.frames.is_empty())
+    }
+
+    /// Weights used to score the repetitions
+    pub fn scoring(&self) -> &Scoring {
+        &self.scoring
+    }
+
+    /// Score of the exercise as the mean of the repetition scores, None without repetitions
+    pub fn score(&self) -> Option<f32> {
+        if self.completed.is_empty() {
+            return None;
+        }
+        Some(self.completed.iter().map(|r| r.score).sum::<f32>() / self.completed.len() as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Metadata, StateWarning};
+
+    fn output(events: Vec<StateEvent>, warnings: &[&str], delta: Option<f32>) -> StateOutput {
+        StateOutput {
+            next_state: None,
+            metadata: Metadata {
+                events,
+                warnings: warnings.iter().map(|name| StateWarning { name: name.to_string(), metadata: None }).collect(),
+                help: None,
+                widgets: vec![],
+                delta: delta.map(|d| HashMap::from([("angle".to_owned(), d)])),
+            },
+        }
+    }
+
+    /// Add the frames at 10 frames per second, each with its state and events
+    fn log(frames: &[(&str, Vec<StateEvent>)]) -> RepetitionLog {
+        let mut log = RepetitionLog::default();
+        for (i, (state, events)) in frames.iter().enumerate() {
+            log.add_frame(i, i as f32 / 10.0, state, &output(events.clone(), &[], None));
+        }
+        log
+    }
+
+    #[test]
+    fn repetition_events_split_the_frames() {
+        let log = log(&[
+            ("down", vec![]), ("up", vec![]), ("up", vec![StateEvent::Repetition]),
+            ("down", vec![]), ("up", vec![StateEvent::Repetition]),
+            ("down", vec![]),
+        ]);
+
+        let completed = log.completed();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(completed[0].frames, 0..3);
+        assert_eq!(completed[0].states, ["down", "up"]);
+        assert_eq!(completed[1].frames, 3..5);
+        assert!((completed[1].start - 0.2).abs() < 1e-4);
+        assert!((completed[1].duration() - 0.2).abs() < 1e-4);
+
+        assert_eq!(log.current().unwrap().frames, 5..6);
+    }
+
+    #[test]
+    fn start_event_discards_the_previous_frames() {
+        let log = log(&[
+            ("entry", vec![]), ("entry", vec![StateEvent::Start]),
+            ("down", vec![]), ("up", vec![StateEvent::Repetition]),
+        ]);
+        assert_eq!(log.completed()[0].frames, 1..4);
+        assert_eq!(log.completed()[0].states, ["entry", "down", "up"]);
+        assert!(log.current().is_none());
+    }
+
+    #[test]
+    fn repetitions_record_warnings_and_delta_bounds() {
+        let mut log = RepetitionLog::new(Scoring::new(HashMap::from([("fast".to_owned(), 0.5)])));
+        log.add_frame(0, 0.0, "down", &output(vec![], &["late", "late"], Some(30.0)));
+        log.add_frame(1, 0.1, "down", &output(vec![], &["fast"], Some(10.0)));
+        log.add_frame(2, 0.2, "up", &output(vec![], &[], Some(50.0)));
+        log.add_frame(3, 0.3, "up", &output(vec![StateEvent::Repetition], &[], None));
+
+        let repetition = &log.completed()[0];
+        assert_eq!(repetition.warnings, HashMap::from([("late".to_owned(), 1), ("fast".to_owned(), 1)]));
+        assert_eq!(repetition.delta["angle"], Bounds { min: 10.0, max: 50.0 });
+        assert!((repetition.score - 62.5).abs() < 1e-4);
+        assert_eq!(log.score(), Some(repetition.score));
+    }
+
+    #[test]
+    fn exercise_without_repetitions_has_no_score() {
+        let log = log(&[("down", vec![]), ("up", vec![])]);
+        assert!(log.completed().is_empty());
+        assert_eq!(log.score(), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+
+use crate::geometry::{inner_angle, joint};
+use crate::{Exercise, Frame};
+
+/// Joint angles analyzed by the range of motion, as the vertex and the two joints forming the angle
+const JOINT_ANGLES: [(&str, [&str; 2]); 4] = [
+    ("elbow", ["shoulder", "wrist"]),
+    ("shoulder", ["hip", "elbow"]),
+    ("hip", ["shoulder", "knee"]),
+    ("knee", ["hip", "ankle"]),
+];
+
+/// Statistics of a joint angle over a set of frames, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AngleStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Number of frames in which the angle was observed
+    pub samples: u32,
+}
+
+impl AngleStats {
+    /// Difference between the maximum and minimum angle
+    pub fn range(&self) -> f32 {
+        self.max - self.min
+    }
+}
+
+/// Statistics of each joint angle, indexed by the vertex joint, for example "left_elbow"
+pub type RangeOfMotion = BTreeMap<String, AngleStats>;
+
+/// Range of motion of a whole exercise and of each of its repetitions
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RangeOfMotionReport {
+    pub exercise: RangeOfMotion,
+    /// In the same order of the completed repetitions
+    pub repetitions: Vec<RangeOfMotion>,
+}
+
+/// Angles analyzed for the joints required by the exercise,
+/// all the angles are analyzed when the exercise does not require any joint
+fn analyzed_angles(required_joints: &[String]) -> Vec<(String, [String; 2])> {
+    let mut angles = vec![];
+    for side in ["left", "right"] {
+        for (vertex, ends) in JOINT_ANGLES {
+            let vertex = format!("{side}_{vertex}");
+            if required_joints.is_empty() || required_joints.contains(&vertex) {
+                angles.push((vertex, ends.map(|j| format!("{side}_{j}"))));
+            }
+        }
+    }
+    angles
+}
+
+/// Compute the range of motion over the frames, angles never observed are not included
+pub fn range_of_motion(frames: &[Frame], required_joints: &[String]) -> RangeOfMotion {
+    let mut result = RangeOfMotion::new();
+    for (vertex, [a, b]) in analyzed_angles(required_joints) {
+        let angles: Vec<f32> = frames.iter()
+            .filter_map(|f| {
+                let s = &f.skeleton;
+                Some(inner_angle(joint(s, &a)?, joint(s, &vertex)?, joint(s, &b)?))
+            })
+            .collect();
+
+        if !angles.is_empty() {
+            result.insert(vertex, AngleStats {
+                min: angles.iter().copied().fold(f32::INFINITY, f32::min),
+                max: angles.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+                mean: angles.iter().sum::<f32>() / angles.len() as f32,
+                samples: angles.len() as u32,
+            });
+        }
+    }
+    result
+}
+
+/// Compute the range of motion of the exercise and of each completed repetition
+pub fn analyze(exercise: &dyn Exercise) -> RangeOfMotionReport {
+    let frames = exercise.frames();
+    let joints = exercise.required_joints();
+    RangeOfMotionReport {
+        exercise: range_of_motion(frames, joints),
+        repetitions: exercise.repetition_log().completed().iter()
+            .map(|r| range_of_motion(&frames[r.frames.clone()], joints))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec2;
+    use crate::{LuaExercise, Skeleton};
+
+    const EPSILON: f32 = 1e-3;
+
+    /// Exercise analyzing every angle, the frames 2 and 4 complete a repetition
+    const SCRIPT: &str = r#"
+        JOINTS = {}
+        STATES = {}
+        function setup(context) end
+        function entry(skeleton, factors, frame)
+            if frame.index == 2 or frame.index == 4 then return stay({ events = { "repetition" } }) end
+            return stay()
+        end
+    "#;
+
+    /// Arms bent at the elbow by the given angles in degrees, the right arm is absent without an angle
+    fn arms(left: f32, right: Option<f32>) -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        for (side, angle) in [("left", Some(left)), ("right", right)] {
+            let Some(angle) = angle else { continue };
+            let (sin, cos) = angle.to_radians().sin_cos();
+            skeleton.insert(format!("{side}_shoulder"), Vec2::new(0.0, -10.0).into());
+            skeleton.insert(format!("{side}_elbow"), Vec2::ZERO.into());
+            skeleton.insert(format!("{side}_wrist"), (Vec2::new(sin, -cos) * 10.0).into());
+        }
+        skeleton
+    }
+
+    /// Exercise that processed the frames with the given elbow angles
+    fn exercise(angles: &[(f32, Option<f32>)]) -> LuaExercise {
+        let mut exercise = LuaExercise::from_string(SCRIPT.to_owned(), "test".to_owned(), String::new(), 2).unwrap();
+        for (index, (left, right)) in angles.iter().enumerate() {
+            exercise.process(&arms(*left, *right), index as u64, index as f32).unwrap();
+        }
+        exercise
+    }
+
+    fn assert_stats(stats: &AngleStats, min: f32, max: f32, mean: f32, samples: u32) {
+        assert!((stats.min - min).abs() < EPSILON, "{stats:?}");
+        assert!((stats.max - max).abs() < EPSILON, "{stats:?}");
+        assert!((stats.mean - mean).abs() < EPSILON, "{stats:?}");
+        assert_eq!(stats.samples, samples);
+    }
+
+    #[test]
+    fn statistics_of_the_exercise_and_of_each_repetition() {
+        let exercise = exercise(&[(90.0, None), (120.0, Some(45.0)), (150.0, None), (60.0, Some(135.0)), (90.0, None), (100.0, None)]);
+        let report = analyze(&exercise);
+
+        assert_eq!(report.exercise.keys().collect::<Vec<_>>(), ["left_elbow", "right_elbow"]);
+        assert_stats(&report.exercise["left_elbow"], 60.0, 150.0, 610.0 / 6.0, 6);
+        assert!((report.exercise["left_elbow"].range() - 90.0).abs() < EPSILON);
+
+        // The frames after the last repetition are only in the range of the exercise
+        assert_eq!(report.repetitions.len(), 2);
+        assert_stats(&report.repetitions[0]["left_elbow"], 90.0, 150.0, 120.0, 3);
+        assert_stats(&report.repetitions[1]["left_elbow"], 60.0, 90.0, 75.0, 2);
+    }
+
+    #[test]
+    fn joints_absent_from_some_frames_use_the_others() {
+        let exercise = exercise(&[(90.0, None), (120.0, Some(45.0)), (150.0, None), (60.0, Some(135.0)), (90.0, None)]);
+        let report = analyze(&exercise);
+        assert_stats(&report.exercise["right_elbow"], 45.0, 135.0, 90.0, 2);
+        assert_stats(&report.repetitions[0]["right_elbow"], 45.0, 45.0, 45.0, 1);
+        assert_stats(&report.repetitions[1]["right_elbow"], 135.0, 135.0, 135.0, 1);
+
+        // Angles never observed are not reported
+        let report = analyze(&self::exercise(&[(90.0, None), (120.0, None), (150.0, None)]));
+        assert!(!report.exercise.contains_key("right_elbow"));
+        assert!(!report.repetitions[0].contains_key("right_elbow"));
+    }
+
+    #[test]
+    fn only_the_required_joints_are_analyzed() {
+        let exercise = exercise(&[(90.0, Some(45.0)), (120.0, Some(60.0))]);
+        let rom = range_of_motion(exercise.frames(), &["right_elbow".to_owned()]);
+        assert_eq!(rom.keys().collect::<Vec<_>>(), ["right_elbow"]);
+        assert_stats(&rom["right_elbow"], 45.0, 60.0, 52.5, 2);
+    }
+}
@@ -0,0 +1,240 @@
+use mlua::prelude::*;
+use mlua::{HookTriggers, StdLib, VmState};
+
+/// Globals of the base library that can load code or change the interpreter, removed in the sandbox
+const UNSAFE_GLOBALS: &[&str] = &["dofile", "loadfile", "load", "loadstring", "require", "collectgarbage"];
+
+/// Number of instructions between two checks of the instruction limit
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Functions catching the errors, wrapped so that they cannot catch the instruction limit.
+/// The table is None for the base library
+const PROTECTED_CALLS: &[(Option<&str>, &str)] = &[(None, "pcall"), (None, "xpcall"), (Some("coroutine"), "resume")];
+
+/// Restrictions applied to the Lua scripts of the exercises.
+/// The scripts are downloaded from the database, so by default they cannot
+/// access the system and a runaway state function is aborted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sandbox {
+    /// Only load the libraries without access to the system (no io, os, debug, package)
+    /// and remove the functions that load code
+    pub restrict_stdlib: bool,
+    /// Maximum number of instructions executed by a single call into the script
+    pub max_instructions: Option<u64>,
+    /// Maximum memory used by the script, in bytes
+    pub max_memory: Option<usize>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            restrict_stdlib: true,
+            max_instructions: Some(1_000_000),
+            max_memory: Some(16 * 1024 * 1024),
+        }
+    }
+}
+
+impl Sandbox {
+    /// Full standard library and no limits, only for trusted scripts
+    pub fn disabled() -> Self {
+        Self { restrict_stdlib: false, max_instructions: None, max_memory: None }
+    }
+}
+
+/// Instructions executed by the current call, stored as app data
+#[derive(Debug, Default)]
+struct InstructionBudget {
+    executed: u64,
+}
+
+/// Create a Lua state with the restrictions of the sandbox
+pub(crate) fn create_lua(sandbox: &Sandbox) -> LuaResult<Lua> {
+    let lua = if sandbox.restrict_stdlib {
+        let lua = Lua::new_with(
+            StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH,
+            LuaOptions::default()
+        )?;
+        for name in UNSAFE_GLOBALS {
+            lua.globals().set(*name, LuaValue::Nil)?;
+        }
+        lua
+    } else {
+        Lua::new()
+    };
+
+    if let Some(max_memory) = sandbox.max_memory {
+        lua.set_memory_limit(max_memory)?;
+    }
+
+    // Abort the call when too many instructions are executed, like an infinite loop.
+    // Once the limit is exceeded every check fails until the budget is reset
+    if let Some(max_instructions) = sandbox.max_instructions {
+        lua.set_app_data(InstructionBudget::default());
+        lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |lua, _| count_instructions(lua, max_instructions));
+        limit_coroutines(&lua, max_instructions)?;
+        protect_calls(&lua, max_instructions)?;
+    }
+
+    Ok(lua)
+}
+
+/// Hook counting the instructions executed by the current call
+fn count_instructions(lua: &Lua, max_instructions: u64) -> LuaResult<VmState> {
+    lua.app_data_mut::<InstructionBudget>().expect("missing instruction budget").executed += HOOK_INTERVAL as u64;
+    check_budget(lua, max_instructions)?;
+    Ok(VmState::Continue)
+}
+
+/// The hook is not inherited by the coroutines, replace the functions creating them
+/// with ones that set the hook on the new coroutine
+fn limit_coroutines(lua: &Lua, max_instructions: u64) -> LuaResult<()> {
+    let Some(coroutine) = lua.globals().get::<Option<LuaTable>>("coroutine")? else {
+        return Ok(());
+    };
+
+    let create = lua.create_function(move |lua, function: LuaFunction| {
+        let thread = lua.create_thread(function)?;
+        thread.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |lua, _| count_instructions(lua, max_instructions));
+        Ok(thread)
+    })?;
+    coroutine.set("create", create)?;
+
+    // Same as the original wrap, on top of the limited create
+    let wrap: LuaFunction = lua.load(r#"
+        local create, resume = coroutine.create, coroutine.resume
+        return function(f)
+            local co = create(f)
+            return function(...)
+                local result = table.pack(resume(co, ...))
+                if not result[1] then error(result[2], 0) end
+                return table.unpack(result, 2, result.n)
+            end
+        end
+    "#).eval()?;
+    coroutine.set("wrap", wrap)?;
+    Ok(())
+}
+
+/// Fails if the instructions executed by the current call exceed the limit
+fn check_budget(lua: &Lua, max_instructions: u64) -> LuaResult<()> {
+    let budget = lua.app_data_ref::<InstructionBudget>().expect("missing instruction budget");
+    if budget.executed > max_instructions {
+        return Err(LuaError::runtime(format!("instruction limit of {} exceeded", max_instructions)));
+    }
+    Ok(())
+}
+
+/// Wrap the functions catching the errors, the instruction limit error is raised again after them
+fn protect_calls(lua: &Lua, max_instructions: u64) -> LuaResult<()> {
+    for (table, name) in PROTECTED_CALLS {
+        let table = match table {
+            Some(table) => match lua.globals().get::<Option<LuaTable>>(*table)? {
+                Some(table) => table,
+                None => continue,
+            },
+            None => lua.globals(),
+        };
+        let Some(original) = table.get::<Option<LuaFunction>>(*name)? else {
+            continue;
+        };
+
+        table.set(*name, lua.create_function(move |lua, args: LuaMultiValue| {
+            let result = original.call::<LuaMultiValue>(args);
+            check_budget(lua, max_instructions)?;
+            result
+        })?)?;
+    }
+    Ok(())
+}
+
+/// Reset the instruction count, must be called before each call into the script
+pub(crate) fn reset_budget(lua: &Lua) {
+    if let Some(mut budget) = lua.app_data_mut::<InstructionBudget>() {
+        budget.executed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+    use super::*;
+
+    /// Run the code in a new sandbox, None if it is still running after a few seconds
+    fn run(code: &'static str) -> Option<LuaResult<()>> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let lua = create_lua(&Sandbox::default()).unwrap();
+            reset_budget(&lua);
+            let _ = sender.send(lua.load(code).exec());
+        });
+        receiver.recv_timeout(Duration::from_secs(5)).ok()
+    }
+
+    fn assert_aborted(code: &'static str, message: &str) {
+        let error = run(code).expect("the script was not aborted").unwrap_err();
+        assert!(error.to_string().contains(message), "unexpected error: {}", error);
+    }
+
+    #[test]
+    fn infinite_loop_is_aborted() {
+        assert_aborted("while true do end", "instruction limit");
+    }
+
+    #[test]
+    fn memory_limit_is_enforced() {
+        assert_aborted("local s = string.rep('x', 32 * 1024 * 1024)", "memory");
+    }
+
+    #[test]
+    fn pcall_cannot_catch_the_instruction_limit() {
+        assert_aborted("while true do pcall(function() while true do end end) end", "instruction limit");
+    }
+
+    #[test]
+    fn xpcall_cannot_catch_the_instruction_limit() {
+        assert_aborted("while true do xpcall(function() while true do end end, function(e) return e end) end", "instruction limit");
+    }
+
+    #[test]
+    fn coroutines_cannot_catch_the_instruction_limit() {
+        assert_aborted("while true do coroutine.resume(coroutine.create(function() while true do end end)) end", "instruction limit");
+        assert_aborted("coroutine.wrap(function() while true do end end)()", "instruction limit");
+    }
+
+    #[test]
+    fn coroutines_still_work() {
+        let result = run(r#"
+            local co = coroutine.wrap(function(a) local b = coroutine.yield(a + 1) return b * 2 end)
+            assert(co(1) == 2)
+            assert(co(5) == 10)
+            local ok, err = coroutine.resume(coroutine.create(function() error("boom") end))
+            assert(not ok and err:find("boom"))
+        "#).unwrap();
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[test]
+    fn pcall_still_catches_script_errors() {
+        let result = run("local ok = pcall(error, 'boom') assert(not ok)").unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn budget_is_reset_between_calls() {
+        let lua = create_lua(&Sandbox::default()).unwrap();
+        let function: LuaFunction = lua.load("return function() for i = 1, 200000 do end end").eval().unwrap();
+        for _ in 0..10 {
+            reset_budget(&lua);
+            function.call::<()>(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn unsafe_globals_are_removed() {
+        let lua = create_lua(&Sandbox::default()).unwrap();
+        for name in UNSAFE_GLOBALS.iter().chain(&["io", "os", "debug", "package"]) {
+            assert!(lua.globals().get::<LuaValue>(*name).unwrap().is_nil(), "{} is available", name);
+        }
+    }
+}
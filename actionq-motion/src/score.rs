@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+/// Weight of the warnings without a configured weight
+pub const DEFAULT_WARNING_WEIGHT: f32 = 1.0;
+
+/// Quality score of the repetitions computed from the warnings.
+///
+/// Each warning lowers the score of a repetition by its weight multiplied by the fraction
+/// of the frames of the repetition in which it was present, so a warning with weight 1.0
+/// present during the whole repetition brings the score to 0.
+#[derive(Debug, Clone, Default)]
+pub struct Scoring {
+    /// Weight of each warning name, configured by the exercise
+    weights: HashMap<String, f32>,
+}
+
+impl Scoring {
+
+    /// Create the scoring with the weight of each warning name
+    pub fn new(weights: HashMap<String, f32>) -> Self {
+        Self { weights }
+    }
+
+    /// Weight of a warning
+    pub fn weight(&self, name: &str) -> f32 {
+        self.weights.get(name).copied().unwrap_or(DEFAULT_WARNING_WEIGHT)
+    }
+
+    /// Score from 0 to 100 of a repetition with the given number of frames
+    /// and the number of frames in which each warning was present
+    pub fn score(&self, warnings: &HashMap<String, u32>, frames: usize) -> f32 {
+        if frames == 0 {
+            return 100.0;
+        }
+        let penalty: f32 = warnings.iter()
+            .map(|(name, count)| self.weight(name) * *count as f32 / frames as f32)
+            .sum();
+        (100.0 * (1.0 - penalty)).clamp(0.0, 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn counts(warnings: &[(&str, u32)]) -> HashMap<String, u32> {
+        warnings.iter().map(|(name, count)| (name.to_string(), *count)).collect()
+    }
+
+    #[test]
+    fn repetitions_without_warnings_are_perfect() {
+        let scoring = Scoring::default();
+        assert_eq!(scoring.score(&HashMap::new(), 10), 100.0);
+        assert_eq!(scoring.score(&counts(&[("late", 3)]), 0), 100.0);
+    }
+
+    #[test]
+    fn warnings_lower_the_score_by_their_duration() {
+        let scoring = Scoring::default();
+        assert!((scoring.score(&counts(&[("late", 5)]), 10) - 50.0).abs() < EPSILON);
+        assert!((scoring.score(&counts(&[("late", 10)]), 10)).abs() < EPSILON);
+    }
+
+    #[test]
+    fn weights_scale_the_penalty() {
+        let scoring = Scoring::new(HashMap::from([("late".to_owned(), 0.5)]));
+        assert_eq!(scoring.weight("late"), 0.5);
+        assert_eq!(scoring.weight("other"), DEFAULT_WARNING_WEIGHT);
+        assert!((scoring.score(&counts(&[("late", 10)]), 10) - 50.0).abs() < EPSILON);
+        assert!((scoring.score(&counts(&[("late", 10), ("other", 2)]), 10) - 30.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn score_is_never_negative() {
+        let scoring = Scoring::default();
+        assert_eq!(scoring.score(&counts(&[("late", 10), ("other", 10)]), 10), 0.0);
+    }
+}
@@ -0,0 +1,12 @@
+//! Helpers shared by the unit tests
+
+/// Sample with the first occurrence of the text replaced
+pub fn sample_with(sample: &str, from: &str, to: &str) -> String {
+    assert!(sample.contains(from), "'{}' is not in the sample", from);
+    sample.replacen(from, to, 1)
+}
+
+/// 1-based line of the first occurrence of the text in the source
+pub fn line_of(source: &str, text: &str) -> usize {
+    source.lines().position(|l| l.contains(text)).expect("text not found") + 1
+}
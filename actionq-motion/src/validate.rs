@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use mlua::prelude::*;
+
+use crate::{sandbox, LuaExercise, Parameters, Sandbox};
+
+/// Problem found in a Lua exercise script
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ValidationIssue {
+    /// The script cannot be loaded or lacks a required global
+    #[error("invalid script: {0}")]
+    Invalid(String),
+    /// A joint in JOINTS is not produced by the pose estimator
+    #[error("unknown joint '{0}' in JOINTS")]
+    UnknownJoint(String),
+    /// A state listed in STATES has no function with the same name
+    #[error("state '{0}' is listed in STATES but there is no function with this name")]
+    MissingStateFunction(String),
+    /// A function is the target of a step but it is not listed in STATES
+    #[error("state '{target}' targeted by '{state}' (line {line}) is not listed in STATES")]
+    UnlistedState { state: String, target: String, line: usize },
+    /// A step targets a state that does not exist
+    #[error("unknown state '{target}' targeted by '{state}' (line {line})")]
+    UnknownStepTarget { state: String, target: String, line: usize },
+    /// A state hook is defined for a state that does not exist
+    #[error("state '{state}' in {table} is not listed in STATES")]
+    UnknownHookState { table: String, state: String },
+    /// A state cannot be reached from the 'entry' state
+    #[error("state '{0}' cannot be reached from 'entry'")]
+    UnreachableState(String),
+}
+
+impl ValidationIssue {
+    /// Errors prevent the exercise from running correctly, the other issues are only warnings
+    pub fn is_error(&self) -> bool {
+        !matches!(self, ValidationIssue::UnreachableState(_))
+    }
+}
+
+/// Literal targets of the 'step' calls in the source, with their 1-based line.
+/// Only string literals are found, targets computed at runtime cannot be checked
+fn step_targets(source: &str, first_line: usize, last_line: usize) -> Vec<(String, usize)> {
+    let mut targets = vec![];
+    for (i, line) in source.lines().enumerate().take(last_line).skip(first_line.saturating_sub(1)) {
+        let code = line.split("--").next().unwrap_or_default();
+        for (position, _) in code.match_indices("step(") {
+
+            // Skip identifiers ending with 'step', like 'next_step('
+            let before = code[..position].chars().next_back();
+            if before.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                continue;
+            }
+
+            let argument = code[position + "step(".len()..].trim_start();
+            let Some(quote) = argument.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                continue;
+            };
+            if let Some(end) = argument[1..].find(quote) {
+                targets.push((argument[1..=end].to_owned(), i + 1));
+            }
+        }
+    }
+    targets
+}
+
+/// Check a Lua exercise script, the joints are the ones produced by the pose estimator.
+/// The script is executed in the default sandbox.
+/// Returns all the issues found, an empty list means that the script is valid
+pub fn validate_lua(script: &str, joints: &[&str]) -> Vec<ValidationIssue> {
+    let mut issues = vec![];
+
+    let ctx = match LuaExercise::create_lua_ctx(&Sandbox::default()) {
+        Ok(ctx) => ctx,
+        Err(e) => return vec![ValidationIssue::Invalid(e.to_string())],
+    };
+    sandbox::reset_budget(&ctx);
+    if let Err(e) = ctx.load(script).exec() {
+        return vec![ValidationIssue::Invalid(e.to_string())];
+    }
+    let globals = ctx.globals();
+
+    let states = match globals.get::<Vec<String>>("STATES") {
+        Ok(states) => states,
+        Err(e) => return vec![ValidationIssue::Invalid(format!("STATES: {}", e))],
+    };
+    for name in ["setup", "entry"] {
+        if globals.get::<LuaFunction>(name).is_err() {
+            issues.push(ValidationIssue::Invalid(format!("missing function '{}'", name)));
+        }
+    }
+
+    match globals.get::<Vec<String>>("JOINTS") {
+        Ok(required) => issues.extend(required.into_iter()
+            .filter(|j| !joints.contains(&j.as_str()))
+            .map(ValidationIssue::UnknownJoint)),
+        Err(e) => issues.push(ValidationIssue::Invalid(format!("JOINTS: {}", e))),
+    }
+
+    if let Err(e) = globals.get::<Option<Parameters>>("PARAMETERS") {
+        issues.push(ValidationIssue::Invalid(format!("PARAMETERS: {}", e)));
+    }
+
+    // The state hooks must refer to existing states
+    for table in ["ON_ENTER", "ON_EXIT"] {
+        match globals.get::<Option<HashMap<String, LuaFunction>>>(table) {
+            Ok(hooks) => issues.extend(hooks.into_iter().flatten()
+                .filter(|(state, _)| state != "entry" && !states.contains(state))
+                .map(|(state, _)| ValidationIssue::UnknownHookState { table: table.to_owned(), state })),
+            Err(e) => issues.push(ValidationIssue::Invalid(format!("{}: {}", table, e))),
+        }
+    }
+
+    // Collect the step targets of each state function
+    let mut graph = HashMap::<String, Vec<String>>::new();
+    let listed: HashSet<&str> = states.iter().map(String::as_str).collect();
+    let entry_and_states = std::iter::once("entry")
+        .chain(states.iter().map(String::as_str).filter(|s| *s != "entry"));
+    for state in entry_and_states {
+        let Ok(function) = globals.get::<LuaFunction>(state) else {
+            if state != "entry" {
+                issues.push(ValidationIssue::MissingStateFunction(state.to_owned()));
+            }
+            continue;
+        };
+
+        let info = function.info();
+        let (Some(first), Some(last)) = (info.line_defined, info.last_line_defined) else {
+            continue;
+        };
+
+        for (target, line) in step_targets(script, first, last) {
+            if !listed.contains(target.as_str()) {
+                let (state, target) = (state.to_owned(), target.clone());
+                issues.push(match globals.get::<LuaFunction>(target.as_str()) {
+                    Ok(_) => ValidationIssue::UnlistedState { state, target, line },
+                    Err(_) => ValidationIssue::UnknownStepTarget { state, target, line },
+                });
+            }
+            graph.entry(state.to_owned()).or_default().push(target);
+        }
+    }
+
+    // Visit the states starting from the entry one, missing states are already reported
+    let mut reached = HashSet::from(["entry".to_owned()]);
+    let mut pending = vec!["entry".to_owned()];
+    while let Some(state) = pending.pop() {
+        for target in graph.get(&state).into_iter().flatten() {
+            if reached.insert(target.clone()) {
+                pending.push(target.clone());
+            }
+        }
+    }
+    issues.extend(states.iter()
+        .filter(|s| !reached.contains(*s) && globals.contains_key(s.as_str()).unwrap_or(false))
+        .map(|s| ValidationIssue::UnreachableState(s.clone())));
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{line_of, sample_with};
+
+    const SAMPLE: &str = include_str!("../data/sample.lua");
+    const JOINTS: &[&str] = &["right_shoulder", "right_elbow", "right_wrist", "left_shoulder", "left_elbow", "left_wrist"];
+
+    #[test]
+    fn sample_is_valid() {
+        assert_eq!(validate_lua(SAMPLE, JOINTS), []);
+    }
+
+    #[test]
+    fn misspelled_step_target_is_reported() {
+        let script = sample_with(SAMPLE, r#"step("down""#, r#"step("dwon""#);
+        let issues = validate_lua(&script, JOINTS);
+        let expected = ValidationIssue::UnknownStepTarget {
+            state: "entry".to_owned(),
+            target: "dwon".to_owned(),
+            line: line_of(&script, r#"step("dwon""#),
+        };
+        assert!(issues.contains(&expected), "{:?}", issues);
+        assert!(expected.is_error());
+        assert_eq!(expected.to_string(), format!("unknown state 'dwon' targeted by 'entry' (line {})", line_of(&script, "dwon")));
+
+        // The states are now reachable only from each other
+        assert!(issues.contains(&ValidationIssue::UnreachableState("down".to_owned())));
+        assert!(issues.contains(&ValidationIssue::UnreachableState("up".to_owned())));
+    }
+
+    #[test]
+    fn unknown_joints_are_reported() {
+        let script = sample_with(SAMPLE, r#""right_wrist" }"#, r#""right_hand" }"#);
+        assert_eq!(validate_lua(&script, JOINTS), [ValidationIssue::UnknownJoint("right_hand".to_owned())]);
+    }
+
+    #[test]
+    fn states_without_function_are_reported() {
+        let script = sample_with(SAMPLE, r#"STATES = { "down", "up" }"#, r#"STATES = { "down", "up", "pause" }"#);
+        assert_eq!(validate_lua(&script, JOINTS), [ValidationIssue::MissingStateFunction("pause".to_owned())]);
+    }
+
+    #[test]
+    fn unlisted_states_are_reported() {
+        let script = sample_with(SAMPLE, r#"STATES = { "down", "up" }"#, r#"STATES = { "down" }"#);
+        let issues = validate_lua(&script, JOINTS);
+        assert!(issues.contains(&ValidationIssue::UnlistedState {
+            state: "down".to_owned(),
+            target: "up".to_owned(),
+            line: line_of(&script, r#"step("up""#),
+        }), "{:?}", issues);
+        assert!(issues.contains(&ValidationIssue::UnknownHookState { table: "ON_ENTER".to_owned(), state: "up".to_owned() }));
+    }
+
+    #[test]
+    fn scripts_that_cannot_run_are_invalid() {
+        let issues = validate_lua("STATES = {", JOINTS);
+        assert!(matches!(issues.as_slice(), [ValidationIssue::Invalid(_)]));
+
+        let issues = validate_lua("STATES = {}\nJOINTS = {}\nfunction entry() end", JOINTS);
+        assert_eq!(issues, [ValidationIssue::Invalid("missing function 'setup'".to_owned())]);
+    }
+
+    #[test]
+    fn only_literal_step_calls_are_targets() {
+        let source = "return step('a')\nnext_step('b')\nstep(target)\nstep(\"c\") -- step('d')";
+        assert_eq!(step_targets(source, 1, 4), [("a".to_owned(), 1), ("c".to_owned(), 4)]);
+        assert_eq!(step_targets(source, 2, 3), []);
+    }
+}
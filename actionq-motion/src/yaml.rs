@@ -0,0 +1,464 @@
+use std::{cell::RefCell, collections::HashMap, fmt, marker::PhantomData, path::Path};
+use serde::{de::{self, MapAccess, Visitor}, Deserialize, Deserializer};
+
+use crate::{fsm::{Condition, ConditionKind, FsmDescriptor, FsmError, FsmExercise, State, Transition, Warning}, ControlFactorRegistry};
+
+/// Names defined by the exercise. The references are checked while they are deserialized,
+/// so that serde_yaml reports the location of the offending item
+struct Definitions {
+    states: Vec<String>,
+    required_control_factors: Vec<String>,
+    registry: ControlFactorRegistry,
+    joints: Vec<String>,
+}
+
+thread_local! {
+    /// Definitions of the exercise being parsed, the names are not checked when missing
+    static DEFINITIONS: RefCell<Option<Definitions>> = const { RefCell::new(None) };
+}
+
+/// Check a name against the definitions of the exercise being parsed
+fn check_definitions(check: impl FnOnce(&Definitions) -> Result<(), FsmError>) -> Result<(), FsmError> {
+    DEFINITIONS.with_borrow(|definitions| definitions.as_ref().map_or(Ok(()), check))
+}
+
+/// Deserialize a string checking it inside the visitor, errors are located at the string itself
+fn deserialize_name<'de, D: Deserializer<'de>>(deserializer: D, check: fn(&Definitions, &str) -> Result<(), FsmError>) -> Result<String, D::Error> {
+    struct NameVisitor(fn(&Definitions, &str) -> Result<(), FsmError>);
+
+    impl Visitor<'_> for NameVisitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a name")
+        }
+
+        fn visit_str<E: de::Error>(self, name: &str) -> Result<String, E> {
+            check_definitions(|definitions| (self.0)(definitions, name)).map_err(E::custom)?;
+            Ok(name.to_owned())
+        }
+    }
+
+    deserializer.deserialize_str(NameVisitor(check))
+}
+
+/// Deserialize a mapping and convert it inside the visitor, errors are located at the mapping
+fn deserialize_checked<'de, D, R, T>(deserializer: D, convert: fn(R) -> Result<T, FsmError>) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    R: Deserialize<'de>,
+{
+    struct CheckedVisitor<R, T>(fn(R) -> Result<T, FsmError>, PhantomData<R>);
+
+    impl<'de, R: Deserialize<'de>, T> Visitor<'de> for CheckedVisitor<R, T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a mapping")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+            let raw = R::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            (self.0)(raw).map_err(de::Error::custom)
+        }
+    }
+
+    deserializer.deserialize_map(CheckedVisitor(convert, PhantomData))
+}
+
+/// Name of a state, must be listed in the states
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StateName(String);
+
+impl<'de> Deserialize<'de> for StateName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_name(deserializer, |definitions, name| {
+            if definitions.states.iter().any(|s| s == name) {
+                Ok(())
+            } else {
+                Err(FsmError::Invalid(format!("state '{}' is not listed in the states", name)))
+            }
+        }).map(StateName)
+    }
+}
+
+/// Name of a joint, must be produced by the pose estimator
+#[derive(Debug)]
+struct JointName(String);
+
+impl<'de> Deserialize<'de> for JointName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_name(deserializer, |definitions, name| {
+            if definitions.joints.iter().any(|j| j == name) {
+                Ok(())
+            } else {
+                Err(FsmError::UnknownJoint(name.to_owned()))
+            }
+        }).map(JointName)
+    }
+}
+
+/// Name of a control factor, must be registered and listed in the required control factors, if any
+#[derive(Debug)]
+struct ControlFactorName(String);
+
+impl<'de> Deserialize<'de> for ControlFactorName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_name(deserializer, |definitions, name| {
+            let listed = &definitions.required_control_factors;
+            if !definitions.registry.contains(name) {
+                Err(FsmError::UnknownControlFactor(name.to_owned()))
+            } else if !listed.is_empty() && !listed.iter().any(|cf| cf == name) {
+                Err(FsmError::UnlistedControlFactor(name.to_owned()))
+            } else {
+                Ok(())
+            }
+        }).map(ControlFactorName)
+    }
+}
+
+/// Type of a condition in the YAML format
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum YamlConditionType {
+    InRange,
+    NotInRange,
+    GreaterThan,
+    LessThan,
+}
+
+/// Range written as "(start, end)", [start, end] or { start: .., end: .. }
+#[derive(Debug, Clone, Copy)]
+struct YamlRange(f32, f32);
+
+impl<'de> Deserialize<'de> for YamlRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Pair(f32, f32),
+            Map { start: f32, end: f32 },
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Pair(start, end) | Repr::Map { start, end } => Ok(YamlRange(start, end)),
+            Repr::Text(text) => {
+                let invalid = || de::Error::custom(format!("invalid range '{}', expected '(start, end)'", text));
+                let inner = text.trim()
+                    .strip_prefix('(').and_then(|t| t.strip_suffix(')'))
+                    .ok_or_else(invalid)?;
+                let (start, end) = inner.split_once(',').ok_or_else(invalid)?;
+                Ok(YamlRange(
+                    start.trim().parse().map_err(|_| invalid())?,
+                    end.trim().parse().map_err(|_| invalid())?
+                ))
+            }
+        }
+    }
+}
+
+/// Condition on a single control factor
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct YamlCondition {
+    #[serde(rename = "type")]
+    kind: YamlConditionType,
+    cf: ControlFactorName,
+    range: Option<YamlRange>,
+    value: Option<f32>,
+}
+
+/// Warning, the condition can be written inline or as a list of conditions
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct YamlWarning {
+    name: String,
+    #[serde(default)]
+    description: String,
+    weight: Option<f32>,
+    #[serde(default)]
+    conditions: Vec<CheckedCondition>,
+    #[serde(rename = "type")]
+    kind: Option<YamlConditionType>,
+    cf: Option<ControlFactorName>,
+    range: Option<YamlRange>,
+    value: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct YamlTransition {
+    to: StateName,
+    /// In milliseconds
+    duration: Option<f32>,
+    #[serde(default)]
+    conditions: Vec<CheckedCondition>,
+    #[serde(default)]
+    emit: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct YamlState {
+    #[serde(default)]
+    warnings: Vec<CheckedWarning>,
+    #[serde(default)]
+    transitions: Vec<CheckedTransition>,
+}
+
+/// Names defined by an exercise in the YAML format, the other fields are ignored
+#[derive(Debug, Deserialize)]
+struct YamlDefinitions {
+    states: Vec<String>,
+    #[serde(default)]
+    required_control_factors: Vec<String>,
+}
+
+/// Exercise definition in the YAML format
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct YamlExercise {
+    /// Name of the exercise, used in the database
+    #[allow(dead_code)]
+    name: String,
+    #[serde(default)]
+    required_joints: Vec<JointName>,
+    #[serde(default)]
+    required_control_factors: Vec<ControlFactorName>,
+    states: Vec<String>,
+    /// Defaults to the first state
+    initial_state: Option<StateName>,
+    #[serde(default)]
+    global_warnings: Vec<CheckedWarning>,
+    #[serde(default)]
+    architecture: HashMap<StateName, YamlState>,
+}
+
+/// Condition converted while it is deserialized
+#[derive(Debug)]
+struct CheckedCondition(Condition);
+
+impl<'de> Deserialize<'de> for CheckedCondition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_checked(deserializer, |c: YamlCondition| c.into_condition().map(CheckedCondition))
+    }
+}
+
+/// Warning converted while it is deserialized
+#[derive(Debug)]
+struct CheckedWarning(Warning);
+
+impl<'de> Deserialize<'de> for CheckedWarning {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_checked(deserializer, |w: YamlWarning| w.into_warning().map(CheckedWarning))
+    }
+}
+
+/// Transition converted while it is deserialized
+#[derive(Debug)]
+struct CheckedTransition(Transition);
+
+impl<'de> Deserialize<'de> for CheckedTransition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_checked(deserializer, |t: YamlTransition| t.into_transition().map(CheckedTransition))
+    }
+}
+
+impl YamlCondition {
+    fn into_condition(self) -> Result<Condition, FsmError> {
+        let condition = condition_kind(self.kind, &self.cf.0, self.range, self.value)?;
+        Ok(Condition { control_factor: self.cf.0, condition })
+    }
+}
+
+/// Create the condition checking that the required range or value is present
+fn condition_kind(kind: YamlConditionType, cf: &str, range: Option<YamlRange>, value: Option<f32>) -> Result<ConditionKind, FsmError> {
+    let missing = |field: &str| FsmError::Invalid(format!("condition on '{}' requires the field '{}'", cf, field));
+    let range = || match range {
+        Some(YamlRange(start, end)) if start <= end => Ok(start..end),
+        Some(YamlRange(start, end)) => Err(FsmError::Invalid(format!("range ({}, {}) of the condition on '{}' starts after its end", start, end, cf))),
+        None => Err(missing("range")),
+    };
+    Ok(match kind {
+        YamlConditionType::InRange => ConditionKind::InRange { range: range()? },
+        YamlConditionType::NotInRange => ConditionKind::NotInRange { range: range()? },
+        YamlConditionType::GreaterThan => ConditionKind::GreaterThan { value: value.ok_or_else(|| missing("value"))? },
+        YamlConditionType::LessThan => ConditionKind::LessThan { value: value.ok_or_else(|| missing("value"))? },
+    })
+}
+
+impl YamlWarning {
+    fn into_warning(self) -> Result<Warning, FsmError> {
+        let mut conditions: Vec<Condition> = self.conditions.into_iter().map(|c| c.0).collect();
+
+        // Inline condition
+        match (self.kind, self.cf) {
+            (Some(kind), Some(ControlFactorName(cf))) => conditions.push(Condition {
+                condition: condition_kind(kind, &cf, self.range, self.value)?,
+                control_factor: cf,
+            }),
+            (None, None) => {},
+            _ => return Err(FsmError::Invalid(format!("warning '{}' requires both 'type' and 'cf'", self.name))),
+        }
+
+        if conditions.is_empty() {
+            return Err(FsmError::Invalid(format!("warning '{}' has no conditions", self.name)));
+        }
+
+        Ok(Warning { name: self.name, description: self.description, weight: self.weight, conditions })
+    }
+}
+
+impl YamlTransition {
+    fn into_transition(self) -> Result<Transition, FsmError> {
+        if let Some(event) = self.emit.iter().find(|e| e.eq_ignore_ascii_case("phase")) {
+            return Err(FsmError::Invalid(format!("the event '{}' has no name, use 'phase:<name>'", event)));
+        }
+
+        Ok(Transition {
+            conditions: self.conditions.into_iter().map(|c| c.0).collect(),
+            to: self.to.0,
+            emit: self.emit,
+            duration: self.duration,
+        })
+    }
+}
+
+impl YamlExercise {
+    fn into_descriptor(mut self) -> Result<FsmDescriptor, FsmError> {
+        let initial_state = self.initial_state.map(|s| s.0)
+            .or_else(|| self.states.first().cloned())
+            .ok_or_else(|| FsmError::Invalid("the exercise has no states".to_owned()))?;
+
+        let mut states = vec![];
+        for name in self.states {
+            let state = self.architecture.remove(&StateName(name.clone())).unwrap_or_default();
+            states.push(State {
+                warnings: state.warnings.into_iter().map(|w| w.0).collect(),
+                transitions: state.transitions.into_iter().map(|t| t.0).collect(),
+                name,
+            });
+        }
+
+        Ok(FsmDescriptor {
+            states,
+            initial_state,
+            warnings: self.global_warnings.into_iter().map(|w| w.0).collect(),
+            required_joints: self.required_joints.into_iter().map(|j| j.0).collect(),
+            required_control_factors: self.required_control_factors.into_iter().map(|cf| cf.0).collect(),
+        })
+    }
+}
+
+/// Parse the exercise checking the references against the names it defines,
+/// the joints are the ones produced by the pose estimator
+fn parse(yaml: &str, joints: &[&str]) -> Result<YamlExercise, serde_yaml::Error> {
+    let names: YamlDefinitions = serde_yaml::from_str(yaml)?;
+    DEFINITIONS.set(Some(Definitions {
+        states: names.states,
+        required_control_factors: names.required_control_factors,
+        registry: ControlFactorRegistry::default(),
+        joints: joints.iter().map(|j| j.to_string()).collect(),
+    }));
+    let exercise = serde_yaml::from_str(yaml);
+    DEFINITIONS.set(None);
+    exercise
+}
+
+impl FsmExercise {
+
+    /// Initialize exercise from a YAML file, the joints are the ones produced by the pose estimator
+    pub fn from_yaml_file(path: &Path, joints: &[&str], name: String, description: String, repetitions_target: u32) -> Result<Self, FsmError> {
+        let yaml = std::fs::read_to_string(path)?;
+        Self::from_yaml(yaml, joints, name, description, repetitions_target)
+    }
+
+    /// Initialize exercise from a YAML string, the joints are the ones produced by the pose estimator.
+    /// The errors contain the location of the offending item
+    pub fn from_yaml(yaml: String, joints: &[&str], name: String, description: String, repetitions_target: u32) -> Result<Self, FsmError> {
+        let descriptor = parse(&yaml, joints)?.into_descriptor()?;
+        Self::from_descriptor(descriptor, name, description, repetitions_target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{line_of, sample_with};
+
+    const SAMPLE: &str = include_str!("../data/sample.yaml");
+    const JOINTS: &[&str] = &["left_ankle", "right_ankle", "left_hip", "right_hip"];
+
+    fn load(yaml: &str) -> Result<FsmExercise, FsmError> {
+        FsmExercise::from_yaml(yaml.to_owned(), JOINTS, "sample".to_owned(), String::new(), 10)
+    }
+
+    /// Message and line of a YAML error
+    fn located(error: FsmError) -> (String, usize) {
+        match error {
+            FsmError::Yaml(e) => (e.to_string(), e.location().expect("error without location").line()),
+            other => panic!("expected a located error, got {}", other),
+        }
+    }
+
+    #[test]
+    fn sample_is_valid() {
+        assert!(load(SAMPLE).is_ok());
+        let descriptor = parse(SAMPLE, JOINTS).unwrap().into_descriptor().unwrap();
+        assert_eq!(descriptor.initial_state, "idle");
+        assert_eq!(descriptor.states.len(), 3);
+        assert_eq!(descriptor.required_joints, ["left_ankle", "right_ankle"]);
+    }
+
+    #[test]
+    fn missing_value_is_located_at_its_condition() {
+        let yaml = sample_with(SAMPLE, "            value: 100.0\n", "");
+        let (message, line) = located(load(&yaml).err().unwrap());
+        assert!(message.contains("requires the field 'value'"), "{}", message);
+        assert_eq!(line, line_of(&yaml, "type: greater_than"));
+    }
+
+    #[test]
+    fn unknown_target_state_is_located_at_its_transition() {
+        let yaml = sample_with(SAMPLE, "to: big_distance", "to: big_distanse");
+        let (message, line) = located(load(&yaml).err().unwrap());
+        assert!(message.contains("'big_distanse'"), "{}", message);
+        assert_eq!(line, line_of(&yaml, "to: big_distanse"));
+    }
+
+    #[test]
+    fn unknown_joints_are_rejected() {
+        let yaml = sample_with(SAMPLE, "- right_ankle", "- foot_left");
+        let (message, line) = located(load(&yaml).err().unwrap());
+        assert!(message.contains("unknown joint 'foot_left'"), "{}", message);
+        assert_eq!(line, line_of(&yaml, "foot_left"));
+    }
+
+    #[test]
+    fn reversed_ranges_are_rejected() {
+        let yaml = sample_with(SAMPLE, "range: (30.0, 70.0)", "range: (70.0, 30.0)");
+        let (message, line) = located(load(&yaml).err().unwrap());
+        assert!(message.contains("starts after its end"), "{}", message);
+        assert_eq!(line, line_of(&yaml, "type: not_in_range"));
+
+        let yaml = sample_with(SAMPLE, "range: (30.0, 70.0)", "range: (30.0, 30.0)");
+        assert!(load(&yaml).is_ok());
+    }
+
+    #[test]
+    fn unlisted_control_factors_are_rejected() {
+        let yaml = sample_with(SAMPLE, "cf: feet_distance_x", "cf: feet_distance");
+        let (message, line) = located(load(&yaml).err().unwrap());
+        assert!(message.contains("'feet_distance' is not listed"), "{}", message);
+        assert_eq!(line, line_of(&yaml, "cf: feet_distance"));
+    }
+
+    #[test]
+    fn states_missing_from_the_list_are_rejected() {
+        let yaml = sample_with(SAMPLE, "  big_distance:\n", "  far:\n");
+        let (message, line) = located(load(&yaml).err().unwrap());
+        assert!(message.contains("state 'far' is not listed"), "{}", message);
+        assert_eq!(line, line_of(&yaml, "  far:"));
+    }
+}
@@ -1,4 +1,3 @@
-
 fn dynlink_jetson_libs() {
     println!("cargo:rustc-link-lib=dylib=jetson-inference");
     println!("cargo:rustc-link-lib=dylib=jetson-utils");
@@ -6,8 +5,8 @@ fn dynlink_jetson_libs() {
 
 fn build_libpose() {
 
-    // Rebuild if the pose.cpp file has changes
-    println!("cargo::rerun-if-changed=cpp/pose.cpp");
+    // Rebuild if the videopose.cpp file has changes
+    println!("cargo::rerun-if-changed=cpp/videopose.cpp");
 
     // Compile artifact libpose.so
     cc::Build::new().cpp(true).flag("-w")
@@ -21,6 +20,12 @@ fn build_libpose() {
 }
 
 fn main() {
+    // The poseNet backend is only available on the Jetson, 
+    // other pose sources do not require any native library
+    if std::env::var_os("CARGO_FEATURE_JETSON").is_none() {
+        return;
+    }
+
     dynlink_jetson_libs();
     build_libpose();
-}
\ No newline at end of file
+}
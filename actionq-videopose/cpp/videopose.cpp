@@ -3,6 +3,8 @@
 #include <jetson-utils/URI.h>
 #include <jetson-inference/poseNet.h>
 
+#include <algorithm>
+
 #if defined(_MSC_VER)
   //  Microsoft 
   #define API __declspec(dllexport)
@@ -19,6 +21,7 @@
 #define COLORS_PATH     "network/colors.txt"
 
 #define KEYPOINTS_COUNT 18
+#define MAX_SUBJECTS 4
 using Keypoint = poseNet::ObjectPose::Keypoint;
 
 static struct {
@@ -33,19 +36,47 @@ static struct {
 
 static uchar3 LAST_FB_DATA[FB_SIZE]; 
 
+/// Keypoint with the confidence of the network
+struct KeypointConfidence {
+    uint32_t ID;
+    float x;
+    float y;
+    /// Value of the confidence map at the keypoint, 0 if not detected
+    float confidence;
+};
+
 struct Frame {
-    /// Subjects present in the scene
+    /// Subjects present in the scene, at most MAX_SUBJECTS
     uint32_t subjects;
-    /// All keypoints (even not detected ones) for the first subject
-    Keypoint keypoints[KEYPOINTS_COUNT];
+    /// Number of detected keypoints of each subject
+    uint32_t keypoints_count[MAX_SUBJECTS];
+    /// Detected keypoints of each subject
+    KeypointConfidence keypoints[MAX_SUBJECTS][KEYPOINTS_COUNT];
     /// Pointer to the last processed framedata
     uchar3* framebuffer;
     /// Size of the framebuffer
     uint32_t w, h;
     /// Error code
     int error;
+    /// Capture timestamp of the frame, in nanoseconds
+    uint64_t timestamp;
 };
 
+/// Sample the confidence map of the network at the keypoint position.
+/// The output layers are in mapped memory, so they can be read from the CPU once Process returns
+static float keypoint_confidence(const Keypoint& kp, uint32_t w, uint32_t h) {
+    const Dims3 dims = g.network->GetOutputDims(0); // confidence map: [keypoints, height, width]
+    const uint32_t cmap_w = DIMS_W(dims);
+    const uint32_t cmap_h = DIMS_H(dims);
+    if (kp.ID >= DIMS_C(dims) || w == 0 || h == 0)
+        return 0.0f;
+
+    const uint32_t x = std::min(cmap_w - 1, (uint32_t)(kp.x / w * cmap_w));
+    const uint32_t y = std::min(cmap_h - 1, (uint32_t)(kp.y / h * cmap_h));
+    const float* cmap = g.network->GetOutputPtr(0);
+    return cmap[(kp.ID * cmap_h + y) * cmap_w + x];
+}
+
 extern "C" {
     /// Create TRT engine, load network
     API int initialize(const char* network_path, const char* pose_path, const char* colors_path);
@@ -103,6 +134,8 @@ API Frame inference_step() {
         }
     }
 
+    result.timestamp = g.camera->GetLastTimestamp();
+
     // Get pose from network
     std::vector<poseNet::ObjectPose> poses;
     if (!g.network->Process(framebuffer, w, h, poses, g.overlay_flags)) {
@@ -110,12 +143,15 @@ API Frame inference_step() {
         return result;
     }
 
-    // Construct pose estimation result
-    result.subjects = poses.size();
-    if (result.subjects != 0) {
-        const auto& keypoints = poses[0].Keypoints;
-        for (int i = 0; i < keypoints.size(); i++)
-            result.keypoints[i] = keypoints[i];
+    // Construct pose estimation result with all the subjects
+    result.subjects = std::min<uint32_t>(poses.size(), MAX_SUBJECTS);
+    for (uint32_t s = 0; s < result.subjects; s++) {
+        const auto& keypoints = poses[s].Keypoints;
+        result.keypoints_count[s] = std::min<uint32_t>(keypoints.size(), KEYPOINTS_COUNT);
+        for (uint32_t i = 0; i < result.keypoints_count[s]; i++) {
+            const auto& kp = keypoints[i];
+            result.keypoints[s][i] = { kp.ID, kp.x, kp.y, keypoint_confidence(kp, w, h) };
+        }
     }
 
     // Copy framebuffer to CPU memory
@@ -0,0 +1,198 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use glam::Vec2;
+
+use crate::{FrameData, Framebuffer, Pose, PoseSource, Result};
+
+#[derive(Debug)]
+struct CppError(i32);
+
+impl std::error::Error for CppError { }
+impl std::fmt::Display for CppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error in the underlying cpp library: {}", self.0)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+struct CppKeypoint {
+    id: u32,
+    x: f32,
+    y: f32,
+    /// Value of the confidence map of the network at the keypoint
+    confidence: f32
+}
+
+/// Maximum number of subjects returned by the cpp library
+const MAX_SUBJECTS: usize = 4;
+
+#[repr(C)]
+struct CppFrameData {
+    subjects: u32,
+    /// Number of detected keypoints of each subject
+    keypoints_count: [u32; MAX_SUBJECTS],
+    keypoints: [[CppKeypoint; 18]; MAX_SUBJECTS],
+    framebuffer: *const u8,
+    w: u32, h: u32,
+    error: i32,
+    timestamp: u64
+}
+
+mod cpp {
+    use super::*;
+
+    #[link(name = "videopose")]
+    extern "C" {
+        /// Create TRT engine, load network
+        pub fn initialize(network: *const u8, pose: *const u8, colors: *const u8) -> i32;
+        /// Start gstreamer video pipeline
+        pub fn inference_start(camera: *const u8, output: *const u8) -> i32;
+         /// Process a single frame
+        pub fn inference_step() -> CppFrameData;
+        /// Stop gstreamer video pipeline
+        pub fn inference_stop();
+        /// Free all resources
+        pub fn drop();
+    }
+}
+
+/// The cpp library stores its state in static globals, only one source can exist at a time
+static INSTANTIATED: AtomicBool = AtomicBool::new(false);
+
+/// Pose source backed by the poseNet network running on the Jetson
+#[derive(Debug)]
+pub struct JetsonSource {
+    /// Video source, for example "/dev/video0"
+    camera: String,
+    /// Video output of the processed frames, for example "webrtc://@:8554/output"
+    output: String,
+    /// Capture timestamp of the first frame
+    epoch: Option<Duration>,
+    /// Number of frames produced
+    frames: u64,
+}
+
+impl JetsonSource {
+
+    /// Create TRT engine and load the network
+    #[tracing::instrument(err)]
+    pub fn new(network: &str, pose: &str, colors: &str, camera: &str, output: &str) -> Result<Self> {
+        if INSTANTIATED.swap(true, Ordering::SeqCst) {
+            return Err("the poseNet backend is already instantiated".into());
+        }
+
+        let n = CString::new(network)?;
+        let p = CString::new(pose)?;
+        let c = CString::new(colors)?;
+
+        tracing::info!("Create TensorRT engine and load network: {:?}, {:?}, {:?}", n, p, c);
+        let err = unsafe { cpp::initialize(n.as_ptr() as *const u8, p.as_ptr() as *const u8, c.as_ptr() as *const u8) };
+        if err != 0 {
+            INSTANTIATED.store(false, Ordering::SeqCst);
+            return Err(Box::new(CppError(err)));
+        }
+
+        Ok(Self {
+            camera: camera.to_owned(),
+            output: output.to_owned(),
+            epoch: None,
+            frames: 0,
+        })
+    }
+}
+
+impl PoseSource for JetsonSource {
+
+    #[tracing::instrument(skip(self), err)]
+    fn start(&mut self) -> Result<()> {
+        tracing::info!("Attach to video source and start inference");
+        let c = CString::new(self.camera.as_str())?;
+        let o = CString::new(self.output.as_str())?;
+        let err = unsafe { cpp::inference_start(c.as_ptr() as *const u8, o.as_ptr() as *const u8) };
+        if err != 0 {
+            return Err(Box::new(CppError(err)));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    fn step(&mut self) -> Result<Option<FrameData>> {
+        tracing::info!("Request process frame");
+        let frame_data: Option<FrameData> = unsafe { cpp::inference_step() }.into();
+
+        // Timestamps are relative to the first captured frame
+        Ok(frame_data.map(|frame| {
+            let epoch = *self.epoch.get_or_insert(frame.timestamp);
+            self.frames += 1;
+            FrameData {
+                timestamp: frame.timestamp.saturating_sub(epoch),
+                index: self.frames - 1,
+                ..frame
+            }
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn stop(&mut self) {
+        tracing::info!("Detach from video source and stop inference");
+        unsafe { cpp::inference_stop() };
+    }
+}
+
+impl Drop for JetsonSource {
+    fn drop(&mut self) {
+        tracing::info!("Drop TensorRT engine and network");
+        unsafe { cpp::drop() };
+        INSTANTIATED.store(false, Ordering::SeqCst);
+    }
+}
+
+impl From<CppKeypoint> for Vec2 {
+    fn from(item: CppKeypoint) -> Vec2 {
+        Vec2::new(item.x, item.y)
+    }
+}
+
+impl From<CppFrameData> for Option<FrameData> {
+    fn from(item: CppFrameData) -> Self {
+        if item.error == 0 && item.subjects != 0 {
+
+            let buffer_size = 1280 * 720 * 3;
+            let mut buffer = Vec::with_capacity(buffer_size);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    item.framebuffer,
+                    buffer.as_mut_ptr(),
+                    buffer_size);
+                buffer.set_len(buffer_size);
+            }
+
+            let subjects = (item.subjects as usize).min(MAX_SUBJECTS);
+            let poses = item.keypoints[..subjects].iter()
+                .zip(item.keypoints_count)
+                .map(|(detected, count)| {
+                    let mut keypoints = vec![Vec2::new(0.0, 0.0); 18];
+                    let mut confidence = vec![0.0; 18];
+                    for kp in &detected[..(count as usize).min(18)] {
+                        keypoints[kp.id as usize] = Vec2::new(kp.x, kp.y);
+                        confidence[kp.id as usize] = kp.confidence;
+                    }
+                    Pose { keypoints, confidence }
+                })
+                .collect();
+
+            return Some(FrameData {
+                framebuffer: Framebuffer {
+                    storage: buffer,
+                    size: (1280, 720)
+                },
+                poses,
+                timestamp: Duration::from_nanos(item.timestamp),
+                index: 0,
+            });
+        }
+        None
+    }
+}
@@ -1,180 +1,143 @@
 #![allow(dead_code)]
 
-use std::ffi::CString;
+use std::time::Duration;
 use glam::Vec2;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
-
-#[derive(Debug)]
-struct CppError(i32);
-
-impl std::error::Error for CppError { }
-impl std::fmt::Display for CppError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error in the underlying cpp library: {}", self.0)
-    }
+#[cfg(feature = "jetson")]
+mod jetson;
+mod synthetic;
+mod tracker;
+
+#[cfg(feature = "jetson")]
+pub use jetson::JetsonSource;
+pub use synthetic::SyntheticSource;
+pub use tracker::{PatientSelection, PatientTracker};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Anything capable of producing human pose estimations frame by frame.
+/// The engine drives the source from a dedicated thread, so implementations are allowed to block.
+pub trait PoseSource: Send {
+    /// Attach to the underlying source and start producing frames
+    fn start(&mut self) -> Result<()>;
+    /// Produce the next frame, returns None if no subject is present in the frame
+    fn step(&mut self) -> Result<Option<FrameData>>;
+    /// Detach from the underlying source
+    fn stop(&mut self);
 }
 
-#[repr(C)]
 #[derive(Debug, Clone)]
-struct CppKeypoint {
-    id: u32,
-    x: f32,
-    y: f32
-}
-
-#[repr(C)]
-struct CppFrameData {
-    subjects: u32,
-    keypoints: [CppKeypoint; 18],
-    framebuffer: *const u8,
-    w: u32, h: u32,
-    error: i32
-}
-
-mod cpp {
-    use super::*;
-
-    #[link(name = "videopose")]
-    extern "C" {
-        /// Create TRT engine, load network
-        pub fn initialize(network: *const u8, pose: *const u8, colors: *const u8) -> i32;
-        /// Start gstreamer video pipeline
-        pub fn inference_start(camera: *const u8, output: *const u8) -> i32;
-         /// Process a single frame
-        pub fn inference_step() -> CppFrameData;
-        /// Stop gstreamer video pipeline
-        pub fn inference_stop();
-        /// Free all resources
-        pub fn drop();
-    }
+pub struct Framebuffer {
+    pub storage: Vec<u8>,
+    pub size: (u32, u32)
 }
 
-#[tracing::instrument(err)]
-pub fn create_hpe_engine(network: &str, pose: &str, colors: &str) -> Result<()> {
-
-    let n = CString::new(network)?;
-    let p = CString::new(pose)?; 
-    let c = CString::new(colors)?;
-
-    tracing::info!("Create TensorRT engine and load network: {:?}, {:?}, {:?}", n, p, c);
-    let err = unsafe { cpp::initialize(n.as_ptr(), p.as_ptr(), c.as_ptr()) };
-    if err != 0 {
-        return Err(Box::new(CppError(err)));
+impl Framebuffer {
+    /// Create a black RGB framebuffer
+    pub fn black(w: u32, h: u32) -> Self {
+        Self {
+            storage: vec![0; (w * h * 3) as usize],
+            size: (w, h)
+        }
     }
-    Ok(())
-}
 
-#[tracing::instrument(err)]
-pub fn inference_start(camera: &str, output: &str) -> Result<()> {
-    tracing::info!("Attach to video source and start inference");
-    let c = CString::new(camera)?;
-    let o = CString::new(output)?;
-    let err = unsafe { cpp::inference_start(c.as_ptr(), o.as_ptr()) };
-    if err != 0 {
-        return Err(Box::new(CppError(err)));
+    /// Draw the keypoints as small white squares, keypoints at (0.0, 0.0) are not available and are skipped
+    pub fn draw_keypoints(&mut self, keypoints: &[Vec2]) {
+        let (w, h) = (self.size.0 as i32, self.size.1 as i32);
+        for kp in keypoints.iter().filter(|kp| **kp != Vec2::ZERO) {
+            for y in (kp.y as i32 - 3)..=(kp.y as i32 + 3) {
+                for x in (kp.x as i32 - 3)..=(kp.x as i32 + 3) {
+                    if x >= 0 && x < w && y >= 0 && y < h {
+                        let i = ((y * w + x) * 3) as usize;
+                        self.storage[i..i + 3].fill(255);
+                    }
+                }
+            }
+        }
     }
-    Ok(())
 }
 
-#[tracing::instrument()]
-pub fn inference_stop() {
-    tracing::info!("Detach from video source and stop inference");
-    unsafe { cpp::inference_stop() };
+/// Keypoints of a person detected in the frame, in the COCO order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pose {
+    /// Position of each keypoint, (0.0, 0.0) if not detected
+    pub keypoints: Vec<Vec2>,
+    /// Confidence of each keypoint between 0 and 1, in the same order of the keypoints.
+    /// A keypoint not detected has confidence 0
+    pub confidence: Vec<f32>,
 }
 
-#[tracing::instrument(err)]
-pub fn inference_step() -> Result<Option<FrameData>> {
-    tracing::info!("Request process frame");
-    let frame_data = unsafe { cpp::inference_step() };
-    Ok(frame_data.into())
-}
+impl Pose {
+    /// Pose of a source that does not estimate the confidence, 1 for the available keypoints
+    /// and 0 for the ones at (0.0, 0.0)
+    pub fn from_keypoints(keypoints: Vec<Vec2>) -> Self {
+        let confidence = keypoints.iter()
+            .map(|kp| if *kp == Vec2::ZERO { 0.0 } else { 1.0 })
+            .collect();
+        Self { keypoints, confidence }
+    }
 
-#[tracing::instrument]
-pub fn drop() {
-    tracing::info!("Drop TensorRT engine and network");
-    unsafe { cpp::drop() };
-}
+    /// Position of the detected keypoints
+    pub fn detected(&self) -> impl Iterator<Item = Vec2> + '_ {
+        self.keypoints.iter()
+            .zip(&self.confidence)
+            .filter(|(_, c)| **c > 0.0)
+            .map(|(kp, _)| *kp)
+    }
 
-#[derive(Debug, Clone)]
-pub struct Framebuffer {
-    pub storage: Vec<u8>,
-    pub size: (u32, u32)
+    /// Top left and bottom right corners of the box containing the detected keypoints
+    pub fn bounds(&self) -> Option<(Vec2, Vec2)> {
+        self.detected().fold(None, |bounds, kp| match bounds {
+            Some((min, max)) => Some((kp.min(min), kp.max(max))),
+            None => Some((kp, kp)),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct FrameData {
     pub framebuffer: Framebuffer,
-    pub keypoints: Vec<Vec2>,
-    pub subjects: u32,
+    /// All the people detected in the frame
+    pub poses: Vec<Pose>,
+    /// Capture time, monotonic since the creation of the source
+    pub timestamp: Duration,
+    /// Sequence number of the frame, monotonic since the creation of the source
+    pub index: u64,
 }
 
 impl FrameData {
-    pub fn split(self) -> (Framebuffer, Vec<Vec2>, u32) {
-        (self.framebuffer, self.keypoints, self.subjects)
-    }
-}
-
-impl From<CppKeypoint> for Vec2 {
-    fn from(item: CppKeypoint) -> Vec2 {
-        Vec2::new(item.x, item.y)
+    /// Number of people detected in the frame
+    pub fn subjects(&self) -> usize {
+        self.poses.len()
     }
-}
-
-impl From<CppFrameData> for Option<FrameData> {
-    fn from(item: CppFrameData) -> Self {
-        if item.error == 0 && item.subjects != 0 {
-
-            let buffer_size = 1280 * 720 * 3;
-            let mut buffer = Vec::with_capacity(buffer_size);
-            unsafe {
-                buffer.set_len(buffer_size);
-                std::ptr::copy_nonoverlapping(
-                    item.framebuffer, 
-                    buffer.as_mut_ptr(), 
-                    buffer_size);
-            }
-
-            let mut keypoints = vec![Vec2::new(0.0, 0.0); 18];
-            for kp in &item.keypoints {
-                keypoints[kp.id as usize] = Vec2::new(kp.x, kp.y);
-            }
 
-            return Some(FrameData {
-                framebuffer: Framebuffer { 
-                    storage: buffer, 
-                    size: (1280, 720) 
-                },
-                subjects: item.subjects,
-                keypoints
-            });
-        }
-        None
+    pub fn split(self) -> (Framebuffer, Vec<Pose>) {
+        (self.framebuffer, self.poses)
     }
 }
 
 pub const SKELETON_COCO_JOINTS: &[&str] = &[
-    "nose",           
-    "left_eye",       
-    "right_eye",      
-    "left_ear",       
-    "right_ear",      
-    "left_shoulder",  
-    "right_shoulder", 
-    "left_elbow",     
-    "right_elbow",    
-    "left_wrist",     
-    "right_wrist",    
-    "left_hip",       
-    "right_hip",      
-    "left_knee",      
-    "right_knee",     
-    "left_ankle",     
-    "right_ankle",    
-    "neck",           
+    "nose",
+    "left_eye",
+    "right_eye",
+    "left_ear",
+    "right_ear",
+    "left_shoulder",
+    "right_shoulder",
+    "left_elbow",
+    "right_elbow",
+    "left_wrist",
+    "right_wrist",
+    "left_hip",
+    "right_hip",
+    "left_knee",
+    "right_knee",
+    "left_ankle",
+    "right_ankle",
+    "neck",
 ];
 
-impl FrameData {
+impl Pose {
     pub fn keypoint_from_name<S: AsRef<str>>(&self, name: S) -> Option<&Vec2> {
 
         // From the COCO skeleton
@@ -197,9 +160,15 @@ impl FrameData {
             "left_ankle" => 15,
             "right_ankle" => 16,
             "neck" => 17,
-            _ => return None            
+            _ => return None
         };
 
         Some(&self.keypoints[id])
     }
+
+    /// Confidence of a keypoint from its name in the COCO skeleton
+    pub fn confidence_from_name<S: AsRef<str>>(&self, name: S) -> Option<f32> {
+        let id = SKELETON_COCO_JOINTS.iter().position(|j| *j == name.as_ref())?;
+        self.confidence.get(id).copied()
+    }
 }
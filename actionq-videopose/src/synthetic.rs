@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+use glam::Vec2;
+
+use crate::{FrameData, Framebuffer, Pose, PoseSource, Result};
+
+const FRAME_SIZE: (u32, u32) = (1280, 720);
+
+/// Standing subject facing the camera, in COCO order, arms along the body
+const BASE_POSE: [Vec2; 18] = [
+    Vec2::new(640.0, 150.0), // nose
+    Vec2::new(625.0, 135.0), // left_eye
+    Vec2::new(655.0, 135.0), // right_eye
+    Vec2::new(605.0, 145.0), // left_ear
+    Vec2::new(675.0, 145.0), // right_ear
+    Vec2::new(580.0, 230.0), // left_shoulder
+    Vec2::new(700.0, 230.0), // right_shoulder
+    Vec2::new(580.0, 340.0), // left_elbow
+    Vec2::new(700.0, 340.0), // right_elbow
+    Vec2::new(580.0, 440.0), // left_wrist
+    Vec2::new(700.0, 440.0), // right_wrist
+    Vec2::new(600.0, 450.0), // left_hip
+    Vec2::new(680.0, 450.0), // right_hip
+    Vec2::new(600.0, 570.0), // left_knee
+    Vec2::new(680.0, 570.0), // right_knee
+    Vec2::new(600.0, 690.0), // left_ankle
+    Vec2::new(680.0, 690.0), // right_ankle
+    Vec2::new(640.0, 230.0), // neck
+];
+
+/// Pose source generating a subject that raises and lowers both arms laterally,
+/// useful to develop and test the system without a camera.
+#[derive(Debug)]
+pub struct SyntheticSource {
+    /// Frames generated per second
+    pub fps: f32,
+    /// Duration of a full arm raise and lowering
+    pub period: Duration,
+    /// Maximum arm abduction, in degrees
+    pub amplitude: f32,
+    /// When the source was started
+    started: Option<Instant>,
+    /// When the source was created, timestamps are relative to this
+    epoch: Instant,
+    /// Number of frames produced
+    frames: u64,
+}
+
+impl Default for SyntheticSource {
+    fn default() -> Self {
+        Self {
+            fps: 15.0,
+            period: Duration::from_secs(4),
+            amplitude: 90.0,
+            started: None,
+            epoch: Instant::now(),
+            frames: 0,
+        }
+    }
+}
+
+impl SyntheticSource {
+
+    /// Rotate the arm joints around the shoulders
+    fn generate_keypoints(&self, elapsed: Duration) -> Vec<Vec2> {
+        let phase = elapsed.as_secs_f32() / self.period.as_secs_f32() * std::f32::consts::TAU;
+        let angle = (self.amplitude * (1.0 - phase.cos()) / 2.0).to_radians();
+
+        let mut keypoints = BASE_POSE.to_vec();
+        for (shoulder, joints, side) in [(5, [7, 9], 1.0), (6, [8, 10], -1.0)] {
+            let pivot = BASE_POSE[shoulder];
+            let rotation = Vec2::from_angle(angle * side);
+            for joint in joints {
+                keypoints[joint] = pivot + rotation.rotate(BASE_POSE[joint] - pivot);
+            }
+        }
+        keypoints
+    }
+}
+
+impl PoseSource for SyntheticSource {
+
+    #[tracing::instrument(skip(self), err)]
+    fn start(&mut self) -> Result<()> {
+        tracing::info!("Start synthetic pose generation");
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    fn step(&mut self) -> Result<Option<FrameData>> {
+        let started = self.started
+            .ok_or("synthetic source is not started")?;
+
+        // Emulate the camera frame rate
+        std::thread::sleep(Duration::from_secs_f32(1.0 / self.fps));
+
+        let keypoints = self.generate_keypoints(started.elapsed());
+        let mut framebuffer = Framebuffer::black(FRAME_SIZE.0, FRAME_SIZE.1);
+        framebuffer.draw_keypoints(&keypoints);
+
+        self.frames += 1;
+        Ok(Some(FrameData {
+            framebuffer,
+            poses: vec![Pose::from_keypoints(keypoints)],
+            timestamp: self.epoch.elapsed(),
+            index: self.frames - 1,
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn stop(&mut self) {
+        tracing::info!("Stop synthetic pose generation");
+        self.started = None;
+    }
+}
@@ -0,0 +1,215 @@
+use std::str::FromStr;
+use glam::Vec2;
+
+use crate::FrameData;
+
+/// How far the patient can move between two frames and still be recognized, as a fraction of their size
+const MAX_MOVEMENT: f32 = 0.5;
+
+/// Consecutive frames without the patient before another person can be selected
+const MAX_LOST_FRAMES: u32 = 30;
+
+/// How the patient is chosen among the people in the frame when no one is locked
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PatientSelection {
+    /// The person with the largest bounding box, usually the closest to the camera
+    #[default]
+    Largest,
+    /// The person closest to the center of the frame
+    MostCentral,
+    /// The first person detected by the network
+    First,
+}
+
+/// Parse the name of the selection: largest, central or first
+impl FromStr for PatientSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest" => Ok(Self::Largest),
+            "central" => Ok(Self::MostCentral),
+            "first" => Ok(Self::First),
+            _ => Err(format!("unknown patient selection: {}", s)),
+        }
+    }
+}
+
+/// Position of a person in the frame
+#[derive(Debug, Clone, Copy)]
+struct Subject {
+    /// Index of the pose in the frame
+    index: usize,
+    /// Center of the bounding box
+    center: Vec2,
+    /// Diagonal of the bounding box
+    size: f32,
+}
+
+/// Last known position of the locked patient
+#[derive(Debug, Clone, Copy)]
+struct Locked {
+    center: Vec2,
+    size: f32,
+    /// Consecutive frames without the patient
+    lost: u32,
+}
+
+/// Keeps the identity of the patient across the frames, so that other people
+/// walking in the scene are not analyzed.
+/// Once selected, the patient is locked and followed frame by frame as the person
+/// closest to their last position, until they leave the scene for a while.
+#[derive(Debug, Clone, Default)]
+pub struct PatientTracker {
+    selection: PatientSelection,
+    locked: Option<Locked>,
+}
+
+impl PatientTracker {
+
+    pub fn new(selection: PatientSelection) -> Self {
+        Self { selection, locked: None }
+    }
+
+    /// True if a patient is locked
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
+    /// Forget the locked patient, the next frame selects a new one
+    pub fn reset(&mut self) {
+        self.locked = None;
+    }
+
+    /// Index of the pose of the patient in the frame, None if the patient is not in the frame
+    pub fn track(&mut self, frame: &FrameData) -> Option<usize> {
+        let subjects: Vec<Subject> = frame.poses.iter()
+            .enumerate()
+            .filter_map(|(index, pose)| {
+                let (min, max) = pose.bounds()?;
+                Some(Subject { index, center: (min + max) / 2.0, size: min.distance(max) })
+            })
+            .collect();
+
+        if let Some(locked) = &mut self.locked {
+            let nearest = subjects.iter()
+                .min_by(|a, b| a.center.distance(locked.center).total_cmp(&b.center.distance(locked.center)));
+
+            match nearest {
+                Some(s) if s.center.distance(locked.center) <= MAX_MOVEMENT * locked.size => {
+                    *locked = Locked { center: s.center, size: s.size, lost: 0 };
+                    return Some(s.index);
+                },
+                _ if locked.lost < MAX_LOST_FRAMES => {
+                    locked.lost += 1;
+                    return None;
+                },
+                _ => {
+                    tracing::info!("patient lost, selecting a new one");
+                    self.locked = None;
+                }
+            }
+        }
+
+        let frame_center = Vec2::new(frame.framebuffer.size.0 as f32, frame.framebuffer.size.1 as f32) / 2.0;
+        let selected = match self.selection {
+            PatientSelection::Largest => subjects.iter().max_by(|a, b| a.size.total_cmp(&b.size)),
+            PatientSelection::MostCentral => subjects.iter()
+                .min_by(|a, b| a.center.distance(frame_center).total_cmp(&b.center.distance(frame_center))),
+            PatientSelection::First => subjects.first(),
+        }?;
+
+        tracing::info!("patient locked at {}", selected.center);
+        self.locked = Some(Locked { center: selected.center, size: selected.size, lost: 0 });
+        Some(selected.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::{Framebuffer, Pose};
+
+    /// Person standing at the position, twice as tall as wide
+    fn person(x: f32, y: f32, width: f32) -> Pose {
+        Pose::from_keypoints(vec![Vec2::new(x - width / 2.0, y - width), Vec2::new(x + width / 2.0, y + width)])
+    }
+
+    fn frame(poses: Vec<Pose>) -> FrameData {
+        FrameData { framebuffer: Framebuffer::black(200, 100), poses, timestamp: Duration::ZERO, index: 0 }
+    }
+
+    #[test]
+    fn selection_picks_the_patient() {
+        let poses = vec![person(20.0, 50.0, 10.0), person(150.0, 50.0, 30.0), person(100.0, 50.0, 20.0)];
+        for (selection, expected) in [
+            (PatientSelection::Largest, 1),
+            (PatientSelection::MostCentral, 2),
+            (PatientSelection::First, 0),
+        ] {
+            let mut tracker = PatientTracker::new(selection);
+            assert_eq!(tracker.track(&frame(poses.clone())), Some(expected));
+            assert!(tracker.is_locked());
+        }
+    }
+
+    #[test]
+    fn patient_is_followed_when_the_order_changes() {
+        let mut tracker = PatientTracker::new(PatientSelection::Largest);
+        assert_eq!(tracker.track(&frame(vec![person(100.0, 50.0, 20.0)])), Some(0));
+
+        // A larger person enters, the patient moves a bit and is now the second pose
+        let poses = vec![person(30.0, 50.0, 40.0), person(105.0, 52.0, 20.0)];
+        assert_eq!(tracker.track(&frame(poses)), Some(1));
+        let poses = vec![person(110.0, 54.0, 20.0), person(30.0, 50.0, 40.0)];
+        assert_eq!(tracker.track(&frame(poses)), Some(0));
+    }
+
+    #[test]
+    fn patient_is_recognized_after_leaving_for_a_while() {
+        let mut tracker = PatientTracker::new(PatientSelection::Largest);
+        tracker.track(&frame(vec![person(100.0, 50.0, 20.0)]));
+
+        // Only another person in the scene, it is not analyzed
+        for _ in 0..MAX_LOST_FRAMES {
+            assert_eq!(tracker.track(&frame(vec![person(30.0, 50.0, 40.0)])), None);
+        }
+        let poses = vec![person(30.0, 50.0, 40.0), person(100.0, 50.0, 20.0)];
+        assert_eq!(tracker.track(&frame(poses)), Some(1));
+    }
+
+    #[test]
+    fn another_patient_is_selected_when_the_patient_is_lost() {
+        let mut tracker = PatientTracker::new(PatientSelection::Largest);
+        tracker.track(&frame(vec![person(100.0, 50.0, 20.0)]));
+        for _ in 0..MAX_LOST_FRAMES {
+            assert_eq!(tracker.track(&frame(vec![])), None);
+        }
+        assert_eq!(tracker.track(&frame(vec![person(30.0, 50.0, 40.0)])), Some(0));
+    }
+
+    #[test]
+    fn reset_selects_the_patient_again() {
+        let mut tracker = PatientTracker::new(PatientSelection::Largest);
+        tracker.track(&frame(vec![person(100.0, 50.0, 20.0)]));
+        tracker.reset();
+        assert!(!tracker.is_locked());
+        let poses = vec![person(100.0, 50.0, 20.0), person(30.0, 50.0, 40.0)];
+        assert_eq!(tracker.track(&frame(poses)), Some(1));
+    }
+
+    #[test]
+    fn poses_without_keypoints_are_ignored() {
+        let mut tracker = PatientTracker::new(PatientSelection::First);
+        let poses = vec![Pose::from_keypoints(vec![Vec2::ZERO; 2]), person(100.0, 50.0, 20.0)];
+        assert_eq!(tracker.track(&frame(poses)), Some(1));
+        assert_eq!(tracker.track(&frame(vec![])), None);
+    }
+
+    #[test]
+    fn selection_is_parsed_by_name() {
+        assert_eq!("central".parse(), Ok(PatientSelection::MostCentral));
+        assert!("closest".parse::<PatientSelection>().is_err());
+    }
+}
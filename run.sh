@@ -1,4 +1,7 @@
 #!/bin/bash
 
+# The poseNet backend is only available with the jetson feature
+cargo build --release --features jetson || exit 1
+
 export LD_LIBRARY_PATH=$LD_LIBRARY_PATH:/usr/local/lib/
 DISPLAY=:0 RUST_LOG=none,prepare_engine=info,prepose=info,motion=info ./target/release/prepare_engine